@@ -0,0 +1,534 @@
+/* Client authentication.
+
+  When registering, a client receives a secret (see `messages::Registered`). Before sending any
+  other query, it must prove that it knows this secret:
+
+   * client -> server: `AuthMessage::Hello { user, nonce }`, with a fresh client nonce,
+   * server -> client: `AuthMessage::Nonce { server, nonce }`, with a fresh server nonce,
+   * client -> server: `AuthMessage::Auth { response }`, the MAC of both nonces,
+   * server -> client: `AuthMessage::Auth { response }`, the MAC of both nonces, with another
+     label, so that the client knows it talks to the server it registered with.
+
+  All these messages are sent as `ClientQuery::Auth`. Once the handshake is complete, both sides
+  derive a session key from the secret and both nonces (see `Session`). All the following
+  datagrams, in both directions, are followed by a MAC computed with this key:
+
+   * queries: MAC of the encoded `Sequence<ClientQuery>`, which covers the `seqid`, the `src`
+     and the content. As sequence numbers must increase, a captured query can't be replayed.
+   * replies: MAC of the `seqid` of the query, followed by the encoded reply, so that a reply
+     can't be mistaken for the reply to another query.
+*/
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crypto_hash::{digest, Algorithm};
+use rand::RngCore;
+
+use crate::core::{Clock, SystemClock};
+use crate::messages::{AuthMessage, ClientId, ServerId};
+
+pub type Secret = [u8; 16];
+pub type Nonce = [u8; 8];
+pub type Mac = [u8; 16];
+
+const BLOCK_SIZE: usize = 64;
+
+/// how long a pending challenge is reserved to the address that asked for it
+pub const CHALLENGE_TTL: Duration = Duration::from_secs(10);
+
+pub fn new_secret() -> Secret {
+  let mut out = Secret::default();
+  rand::thread_rng().fill_bytes(&mut out);
+  out
+}
+
+pub fn new_nonce() -> Nonce {
+  let mut out = Nonce::default();
+  rand::thread_rng().fill_bytes(&mut out);
+  out
+}
+
+/// HMAC-SHA256 of the concatenation of `parts`, truncated to 16 bytes
+pub fn mac(key: &[u8], parts: &[&[u8]]) -> Mac {
+  let mut block = [0u8; BLOCK_SIZE];
+  if key.len() > BLOCK_SIZE {
+    let hashed = digest(Algorithm::SHA256, key);
+    block[..hashed.len()].copy_from_slice(&hashed);
+  } else {
+    block[..key.len()].copy_from_slice(key);
+  }
+  let mut inner = block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>();
+  for part in parts {
+    inner.extend_from_slice(part);
+  }
+  let mut outer = block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>();
+  outer.extend(digest(Algorithm::SHA256, &inner));
+  let mut out = Mac::default();
+  out.copy_from_slice(&digest(Algorithm::SHA256, &outer)[..16]);
+  out
+}
+
+/// constant time comparison
+pub fn verify(expected: &Mac, actual: &Mac) -> bool {
+  expected
+    .iter()
+    .zip(actual.iter())
+    .fold(0, |acc, (a, b)| acc | (a ^ b))
+    == 0
+}
+
+fn handshake_mac(
+  label: &[u8],
+  secret: &Secret,
+  user: &ClientId,
+  server: &ServerId,
+  cnonce: &Nonce,
+  snonce: &Nonce,
+) -> Mac {
+  mac(
+    secret,
+    &[
+      label,
+      user.0.as_bytes(),
+      server.0.as_bytes(),
+      cnonce,
+      snonce,
+    ],
+  )
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthError {
+  /// no secret was issued for this client
+  UnknownClient(ClientId),
+  /// the `Hello` message is not for the client that sent it
+  UserMismatch(ClientId),
+  /// the message is not the expected step of the handshake
+  UnexpectedMessage,
+  BadResponse,
+  /// the MAC of a message is missing or invalid
+  BadMac,
+}
+
+impl std::fmt::Display for AuthError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      AuthError::UnknownClient(clientid) => write!(f, "UnknownClient({})", clientid),
+      AuthError::UserMismatch(clientid) => write!(f, "UserMismatch({})", clientid),
+      AuthError::UnexpectedMessage => "UnexpectedMessage".fmt(f),
+      AuthError::BadResponse => "BadResponse".fmt(f),
+      AuthError::BadMac => "BadMac".fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Session key, shared by the client and the server once the handshake is complete
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+  key: Mac,
+}
+
+impl Session {
+  fn new(
+    secret: &Secret,
+    user: &ClientId,
+    server: &ServerId,
+    cnonce: &Nonce,
+    snonce: &Nonce,
+  ) -> Self {
+    Session {
+      key: handshake_mac(b"session", secret, user, server, cnonce, snonce),
+    }
+  }
+
+  fn seal(&self, parts: &[&[u8]], packet: &mut Vec<u8>) {
+    let mut all = parts.to_vec();
+    all.push(packet);
+    let tag = mac(&self.key, &all);
+    packet.extend_from_slice(&tag);
+  }
+
+  fn open<'a>(&self, parts: &[&[u8]], packet: &'a [u8]) -> Result<&'a [u8], AuthError> {
+    if packet.len() < 16 {
+      return Err(AuthError::BadMac);
+    }
+    let (payload, tag) = packet.split_at(packet.len() - 16);
+    let mut actual = Mac::default();
+    actual.copy_from_slice(tag);
+    let mut all = parts.to_vec();
+    all.push(payload);
+    if verify(&mac(&self.key, &all), &actual) {
+      Ok(payload)
+    } else {
+      Err(AuthError::BadMac)
+    }
+  }
+
+  /// appends the MAC to an encoded `Sequence<ClientQuery>`
+  pub fn seal_query(&self, packet: &mut Vec<u8>) {
+    self.seal(&[b"query"], packet)
+  }
+
+  /// checks the MAC of a query, and returns the encoded `Sequence<ClientQuery>`
+  pub fn open_query<'a>(&self, packet: &'a [u8]) -> Result<&'a [u8], AuthError> {
+    self.open(&[b"query"], packet)
+  }
+
+  /// appends the MAC to the encoded reply to the query `seqid`
+  pub fn seal_reply(&self, seqid: u128, packet: &mut Vec<u8>) {
+    self.seal(&[b"reply", &seqid.to_le_bytes()], packet)
+  }
+
+  /// checks the MAC of the reply to the query `seqid`, and returns the encoded reply
+  pub fn open_reply<'a>(&self, seqid: u128, packet: &'a [u8]) -> Result<&'a [u8], AuthError> {
+    self.open(&[b"reply", &seqid.to_le_bytes()], packet)
+  }
+}
+
+struct Challenge {
+  peer: SocketAddr,
+  cnonce: Nonce,
+  snonce: Nonce,
+  issued: Instant,
+}
+
+/// Server side of the handshake, keeps track of the secrets and authenticated clients
+pub struct Authenticator {
+  server: ServerId,
+  clock: Arc<dyn Clock>,
+  secrets: HashMap<ClientId, Secret>,
+  challenges: HashMap<ClientId, Challenge>,
+  sessions: HashMap<ClientId, Session>,
+}
+
+impl Authenticator {
+  pub fn new(server: ServerId) -> Self {
+    Self::with_clock(server, Arc::new(SystemClock))
+  }
+
+  /// an authenticator that reads the time from `clock`, for the expiry of the challenges
+  pub fn with_clock(server: ServerId, clock: Arc<dyn Clock>) -> Self {
+    Authenticator {
+      server,
+      clock,
+      secrets: HashMap::new(),
+      challenges: HashMap::new(),
+      sessions: HashMap::new(),
+    }
+  }
+
+  /// issues the secret for a newly registered client
+  pub fn register(&mut self, user: ClientId) -> Secret {
+    let secret = new_secret();
+    self.secrets.insert(user, secret);
+    secret
+  }
+
+  /// true if a secret was issued for `user`, that is if it is a local client
+  pub fn is_registered(&self, user: &ClientId) -> bool {
+    self.secrets.contains_key(user)
+  }
+
+  /// handles a step of the handshake for `src`, sent from `peer`, and returns the reply
+  pub fn handle(
+    &mut self,
+    peer: SocketAddr,
+    src: ClientId,
+    msg: AuthMessage,
+  ) -> Result<AuthMessage, AuthError> {
+    let secret = *self
+      .secrets
+      .get(&src)
+      .ok_or(AuthError::UnknownClient(src))?;
+    match msg {
+      AuthMessage::Hello { user, nonce } => {
+        if user != src {
+          return Err(AuthError::UserMismatch(user));
+        }
+        // the pending challenge can only be replaced from the same address, or once it expired
+        let now = self.clock.now();
+        if let Some(c) = self.challenges.get(&src) {
+          if c.peer != peer && now.duration_since(c.issued) < CHALLENGE_TTL {
+            return Err(AuthError::UnexpectedMessage);
+          }
+        }
+        // the current session is kept until the new handshake completes
+        let snonce = new_nonce();
+        self.challenges.insert(
+          src,
+          Challenge {
+            peer,
+            cnonce: nonce,
+            snonce,
+            issued: now,
+          },
+        );
+        Ok(AuthMessage::Nonce {
+          server: self.server,
+          nonce: snonce,
+        })
+      }
+      AuthMessage::Auth { response } => {
+        let challenge = match self.challenges.get(&src) {
+          Some(c) if c.peer == peer => c,
+          _ => return Err(AuthError::UnexpectedMessage),
+        };
+        let expected = handshake_mac(
+          b"client",
+          &secret,
+          &src,
+          &self.server,
+          &challenge.cnonce,
+          &challenge.snonce,
+        );
+        if !verify(&expected, &response) {
+          // a failed attempt must start over with new nonces
+          self.challenges.remove(&src);
+          return Err(AuthError::BadResponse);
+        }
+        let response = handshake_mac(
+          b"server",
+          &secret,
+          &src,
+          &self.server,
+          &challenge.cnonce,
+          &challenge.snonce,
+        );
+        let session = Session::new(
+          &secret,
+          &src,
+          &self.server,
+          &challenge.cnonce,
+          &challenge.snonce,
+        );
+        self.challenges.remove(&src);
+        self.sessions.insert(src, session);
+        Ok(AuthMessage::Auth { response })
+      }
+      AuthMessage::Nonce { .. } => Err(AuthError::UnexpectedMessage),
+    }
+  }
+
+  /// the session of `src`, if it completed the handshake
+  pub fn session(&self, src: &ClientId) -> Option<Session> {
+    self.sessions.get(src).copied()
+  }
+}
+
+/// Client side of the handshake
+pub struct Handshake {
+  user: ClientId,
+  secret: Secret,
+  cnonce: Nonce,
+  /// expected response from the server, and session key
+  expected: Option<(Mac, Session)>,
+}
+
+impl Handshake {
+  pub fn new(user: ClientId, secret: Secret) -> Self {
+    Handshake {
+      user,
+      secret,
+      cnonce: new_nonce(),
+      expected: None,
+    }
+  }
+
+  pub fn hello(&self) -> AuthMessage {
+    AuthMessage::Hello {
+      user: self.user,
+      nonce: self.cnonce,
+    }
+  }
+
+  /// answers the `Nonce` message from the server
+  pub fn respond(&mut self, msg: &AuthMessage) -> Result<AuthMessage, AuthError> {
+    match msg {
+      AuthMessage::Nonce { server, nonce } => {
+        let response = handshake_mac(
+          b"client",
+          &self.secret,
+          &self.user,
+          server,
+          &self.cnonce,
+          nonce,
+        );
+        let expected = handshake_mac(
+          b"server",
+          &self.secret,
+          &self.user,
+          server,
+          &self.cnonce,
+          nonce,
+        );
+        let session = Session::new(&self.secret, &self.user, server, &self.cnonce, nonce);
+        self.expected = Some((expected, session));
+        Ok(AuthMessage::Auth { response })
+      }
+      _ => Err(AuthError::UnexpectedMessage),
+    }
+  }
+
+  /// checks the final message from the server, and returns the session key
+  pub fn finish(&self, msg: &AuthMessage) -> Result<Session, AuthError> {
+    match (msg, &self.expected) {
+      (AuthMessage::Auth { response }, Some((expected, session))) => {
+        if verify(expected, response) {
+          Ok(*session)
+        } else {
+          Err(AuthError::BadResponse)
+        }
+      }
+      _ => Err(AuthError::UnexpectedMessage),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::TestClock;
+
+  fn peer() -> SocketAddr {
+    "127.0.0.1:4000".parse().unwrap()
+  }
+
+  #[test]
+  fn hmac_sha256() {
+    // RFC 4231, test case 2
+    let out = mac(b"Jefe", &[b"what do ya want ", b"for nothing?"]);
+    assert_eq!(
+      out,
+      [
+        0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75,
+        0xc7
+      ]
+    );
+  }
+
+  #[test]
+  fn handshake() {
+    let user = ClientId::default();
+    let mut authenticator = Authenticator::new(ServerId::default());
+    let secret = authenticator.register(user);
+    let mut client = Handshake::new(user, secret);
+
+    assert!(authenticator.session(&user).is_none());
+    let nonce = authenticator.handle(peer(), user, client.hello()).unwrap();
+    let response = client.respond(&nonce).unwrap();
+    // the response must come from the address that started the handshake
+    assert_eq!(
+      authenticator.handle("127.0.0.1:4001".parse().unwrap(), user, response.clone()),
+      Err(AuthError::UnexpectedMessage)
+    );
+    let proof = authenticator.handle(peer(), user, response).unwrap();
+    let session = client.finish(&proof).unwrap();
+    assert!(authenticator.session(&user) == Some(session));
+  }
+
+  #[test]
+  fn session_mac() {
+    let session = Session { key: [3; 16] };
+    let other = Session { key: [4; 16] };
+
+    let mut query = vec![1, 2, 3];
+    session.seal_query(&mut query);
+    assert_eq!(query.len(), 3 + 16);
+    assert_eq!(session.open_query(&query), Ok(&[1, 2, 3][..]));
+    assert_eq!(other.open_query(&query), Err(AuthError::BadMac));
+    // a query is not a valid reply
+    assert_eq!(session.open_reply(1, &query), Err(AuthError::BadMac));
+    assert_eq!(session.open_query(&query[..10]), Err(AuthError::BadMac));
+    let mut tampered = query.clone();
+    tampered[0] = 5;
+    assert_eq!(session.open_query(&tampered), Err(AuthError::BadMac));
+
+    let mut reply = vec![4, 5];
+    session.seal_reply(7, &mut reply);
+    assert_eq!(session.open_reply(7, &reply), Ok(&[4, 5][..]));
+    // the reply is bound to the query
+    assert_eq!(session.open_reply(8, &reply), Err(AuthError::BadMac));
+  }
+
+  #[test]
+  fn handshake_failures() {
+    let user = ClientId::default();
+    let other = ClientId::default();
+    let mut authenticator = Authenticator::new(ServerId::default());
+    let secret = authenticator.register(user);
+
+    // unknown client
+    let client = Handshake::new(other, secret);
+    assert_eq!(
+      authenticator.handle(peer(), other, client.hello()),
+      Err(AuthError::UnknownClient(other))
+    );
+    // hello for another client
+    assert_eq!(
+      authenticator.handle(peer(), user, client.hello()),
+      Err(AuthError::UserMismatch(other))
+    );
+    // response without a challenge
+    assert_eq!(
+      authenticator.handle(peer(), user, AuthMessage::Auth { response: [0; 16] }),
+      Err(AuthError::UnexpectedMessage)
+    );
+
+    // wrong secret
+    let mut client = Handshake::new(user, new_secret());
+    let nonce = authenticator.handle(peer(), user, client.hello()).unwrap();
+    let response = client.respond(&nonce).unwrap();
+    assert_eq!(
+      authenticator.handle(peer(), user, response.clone()),
+      Err(AuthError::BadResponse)
+    );
+    // the challenge can't be retried
+    assert_eq!(
+      authenticator.handle(peer(), user, response),
+      Err(AuthError::UnexpectedMessage)
+    );
+    assert!(authenticator.session(&user).is_none());
+  }
+
+  #[test]
+  fn handshake_hijack() {
+    let user = ClientId::default();
+    let clock = TestClock::new();
+    let mut authenticator = Authenticator::with_clock(ServerId::default(), Arc::new(clock.clone()));
+    let secret = authenticator.register(user);
+    let mut client = Handshake::new(user, secret);
+    let nonce = authenticator.handle(peer(), user, client.hello()).unwrap();
+    let proof = authenticator
+      .handle(peer(), user, client.respond(&nonce).unwrap())
+      .unwrap();
+    let session = client.finish(&proof).unwrap();
+
+    // a spoofed hello neither closes the session, nor replaces a pending challenge
+    let attacker: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+    let mut client = Handshake::new(user, secret);
+    let nonce = authenticator.handle(peer(), user, client.hello()).unwrap();
+    assert_eq!(
+      authenticator.handle(attacker, user, Handshake::new(user, secret).hello()),
+      Err(AuthError::UnexpectedMessage)
+    );
+    assert!(authenticator.session(&user) == Some(session));
+    let proof = authenticator
+      .handle(peer(), user, client.respond(&nonce).unwrap())
+      .unwrap();
+    let renewed = client.finish(&proof).unwrap();
+    assert!(authenticator.session(&user) == Some(renewed));
+
+    // an abandoned challenge can be replaced once it expired
+    authenticator.handle(peer(), user, client.hello()).unwrap();
+    clock.advance(CHALLENGE_TTL / 2);
+    assert!(authenticator
+      .handle(attacker, user, client.hello())
+      .is_err());
+    clock.advance(CHALLENGE_TTL / 2);
+    assert!(authenticator.handle(attacker, user, client.hello()).is_ok());
+  }
+}
@@ -1,21 +1,74 @@
 use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 
 use crate::messages::{
-  ClientError, ClientId, ClientMessage, ClientPollReply, ClientReply, Sequence, ServerId,
+  ClientError, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
+  ServerId,
 };
 use crate::messages::{ServerMessage, ServerReply};
 
 pub const MAILBOX_SIZE: usize = 256;
 
+/// number of servers a message can go through before being dropped
+pub const MAX_HOPS: usize = 32;
+
 #[async_trait]
 pub trait SpamChecker {
   async fn is_user_spammer(&self, name: &str) -> bool;
   async fn is_ip_spammer(&self, name: &IpAddr) -> bool;
 }
 
+/// Source of time for the servers, so that time dependent behaviour (route expiry, delayed
+/// messages) can be tested without waiting.
+pub trait Clock: Send + Sync {
+  fn now(&self) -> Instant;
+}
+
+/// the real time
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+}
+
+/// a clock that only moves when advanced, its clones share the same time
+#[derive(Clone)]
+pub struct TestClock {
+  now: Arc<Mutex<Instant>>,
+}
+
+impl TestClock {
+  pub fn new() -> Self {
+    TestClock {
+      now: Arc::new(Mutex::new(Instant::now())),
+    }
+  }
+
+  pub fn advance(&self, delta: Duration) {
+    *self.now.lock().unwrap() += delta;
+  }
+}
+
+impl Default for TestClock {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Clock for TestClock {
+  fn now(&self) -> Instant {
+    *self.now.lock().unwrap()
+  }
+}
+
 #[async_trait]
 pub trait MessageServer<C: SpamChecker> {
   /// group name
@@ -24,6 +77,15 @@ pub trait MessageServer<C: SpamChecker> {
   /// create a new server, this is the constructor function
   fn new(checker: C, id: ServerId) -> Self;
 
+  /// create a new server that reads the time from `clock`
+  /// servers without time dependent behaviour can ignore it
+  fn with_clock(checker: C, id: ServerId, _clock: Arc<dyn Clock>) -> Self
+  where
+    Self: Sized,
+  {
+    Self::new(checker, id)
+  }
+
   /// register a new client, that will then be able to send and receive messages.
   /// The first argument is the client screen name.
   ///
@@ -45,6 +107,7 @@ pub trait MessageServer<C: SpamChecker> {
   /// * if the user is unknown, it might be that it is remote, so messages should be kept until the user becomes known
   ///   as a result, the "Delayed" message should be sent
   /// * until polled, messages are to be stored. There is a maximum mailbox size after which an error should be returned
+  /// * there is one reply per recipient, the recipients behind the same next hop can share a `Transfer`
   async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply>;
 
   /// handles a server message
@@ -55,8 +118,165 @@ pub trait MessageServer<C: SpamChecker> {
   /// gives the best route to a server
   /// as a first approximation, you can give any route
   async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>>;
+
+  /// called regularly by the server, for periodic tasks such as expiring delayed messages
+  async fn tick(&self) {}
+
+  /// the federation as seen by this server, as a Graphviz document, see `routing::Topology::dot`
+  /// servers that do not keep track of the topology return None
+  async fn topology_dot(&self) -> Option<String> {
+    None
+  }
+}
+
+/// Object safe version of `MessageServer`, so that servers can be chosen at runtime and used as
+/// `Box<dyn DynMessageServer>`. The only sequenced messages are client queries.
+/// Any `MessageServer` can be used through a `DynAdapter`.
+#[async_trait]
+pub trait DynMessageServer: Send + Sync {
+  /// see `MessageServer::GROUP_NAME`
+  fn group_name(&self) -> &'static str;
+  async fn register_local_client(&self, src_ip: IpAddr, name: String) -> Option<ClientId>;
+  async fn list_users(&self) -> HashMap<ClientId, String>;
+  async fn handle_sequenced_message(
+    &self,
+    msg: Sequence<ClientQuery>,
+  ) -> Result<ClientQuery, ClientError>;
+  async fn client_poll(&self, client: ClientId) -> ClientPollReply;
+  async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply>;
+  async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply;
+  async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>>;
+  async fn tick(&self);
+  async fn topology_dot(&self) -> Option<String>;
 }
 
+/// a `MessageServer` seen as a `DynMessageServer`
+pub struct DynAdapter<C, M> {
+  server: M,
+  // the spam checker type is only needed to pick the `MessageServer` implementation
+  checker: PhantomData<fn() -> C>,
+}
+
+impl<C: SpamChecker, M: MessageServer<C>> DynAdapter<C, M> {
+  pub fn new(server: M) -> Self {
+    DynAdapter {
+      server,
+      checker: PhantomData,
+    }
+  }
+
+  pub fn into_inner(self) -> M {
+    self.server
+  }
+}
+
+impl<C: SpamChecker + 'static, M: MessageServer<C> + Send + Sync + 'static> DynAdapter<C, M> {
+  /// the server, as a trait object
+  pub fn boxed(server: M) -> Box<dyn DynMessageServer> {
+    Box::new(Self::new(server))
+  }
+}
+
+#[async_trait]
+impl<C: SpamChecker, M: MessageServer<C> + Send + Sync> DynMessageServer for DynAdapter<C, M> {
+  fn group_name(&self) -> &'static str {
+    M::GROUP_NAME
+  }
+
+  async fn register_local_client(&self, src_ip: IpAddr, name: String) -> Option<ClientId> {
+    self.server.register_local_client(src_ip, name).await
+  }
+
+  async fn list_users(&self) -> HashMap<ClientId, String> {
+    self.server.list_users().await
+  }
+
+  async fn handle_sequenced_message(
+    &self,
+    msg: Sequence<ClientQuery>,
+  ) -> Result<ClientQuery, ClientError> {
+    self.server.handle_sequenced_message(msg).await
+  }
+
+  async fn client_poll(&self, client: ClientId) -> ClientPollReply {
+    self.server.client_poll(client).await
+  }
+
+  async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply> {
+    self.server.handle_client_message(src, msg).await
+  }
+
+  async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply {
+    self.server.handle_server_message(msg).await
+  }
+
+  async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>> {
+    self.server.route_to(destination).await
+  }
+
+  async fn tick(&self) {
+    self.server.tick().await
+  }
+
+  async fn topology_dot(&self) -> Option<String> {
+    self.server.topology_dot().await
+  }
+}
+
+/// pointers to servers are servers too, so that they can be shared or boxed
+macro_rules! forward_dyn_message_server {
+  ($pointer:ident) => {
+    #[async_trait]
+    impl<T: DynMessageServer + ?Sized> DynMessageServer for $pointer<T> {
+      fn group_name(&self) -> &'static str {
+        (**self).group_name()
+      }
+
+      async fn register_local_client(&self, src_ip: IpAddr, name: String) -> Option<ClientId> {
+        (**self).register_local_client(src_ip, name).await
+      }
+
+      async fn list_users(&self) -> HashMap<ClientId, String> {
+        (**self).list_users().await
+      }
+
+      async fn handle_sequenced_message(
+        &self,
+        msg: Sequence<ClientQuery>,
+      ) -> Result<ClientQuery, ClientError> {
+        (**self).handle_sequenced_message(msg).await
+      }
+
+      async fn client_poll(&self, client: ClientId) -> ClientPollReply {
+        (**self).client_poll(client).await
+      }
+
+      async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply> {
+        (**self).handle_client_message(src, msg).await
+      }
+
+      async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply {
+        (**self).handle_server_message(msg).await
+      }
+
+      async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>> {
+        (**self).route_to(destination).await
+      }
+
+      async fn tick(&self) {
+        (**self).tick().await
+      }
+
+      async fn topology_dot(&self) -> Option<String> {
+        (**self).topology_dot().await
+      }
+    }
+  };
+}
+
+forward_dyn_message_server!(Box);
+forward_dyn_message_server!(Arc);
+
 // a spam checker that does nothing
 #[derive(Clone, Copy, Default)]
 pub struct DefaultChecker {}
@@ -1,7 +1,12 @@
+pub mod auth;
 pub mod client;
 pub mod core;
 pub mod messages;
+pub mod middleware;
 pub mod netproto;
+pub mod peers;
+pub mod routing;
+pub mod sim;
 pub mod solutions;
 #[cfg(test)]
 pub mod testing;
@@ -72,13 +72,51 @@ impl std::fmt::Display for ServerId {
   }
 }
 
+/// parses the uuid of a client, such as `67e55044-10b1-426f-9247-bb680e5fe0c8`
+impl std::str::FromStr for ClientId {
+  type Err = uuid::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Uuid::parse_str(s).map(ClientId)
+  }
+}
+
+/// parses the uuid of a server, such as `67e55044-10b1-426f-9247-bb680e5fe0c8`
+impl std::str::FromStr for ServerId {
+  type Err = uuid::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Uuid::parse_str(s).map(ServerId)
+  }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Sequence<A> {
   pub seqid: u128,
+  /// followed on the wire by the 64 bits `SEQUENCE_STAMP` field
+  #[serde(with = "sequence_src")]
   pub src: ClientId,
   pub content: A,
 }
 
+/// value of the 64 bits field between the source and the content of a sequenced message, as found
+/// in the protocol reference vectors; it is not kept when decoding
+pub const SEQUENCE_STAMP: u64 = 161_666_813_615;
+
+mod sequence_src {
+  use super::{ClientId, SEQUENCE_STAMP};
+  use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+  pub fn serialize<S: Serializer>(src: &ClientId, s: S) -> Result<S::Ok, S::Error> {
+    (src, SEQUENCE_STAMP).serialize(s)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<ClientId, D::Error> {
+    let (src, _stamp) = <(ClientId, u64)>::deserialize(d)?;
+    Ok(src)
+  }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum AuthMessage {
   Hello { user: ClientId, nonce: [u8; 8] },
@@ -92,6 +130,16 @@ pub enum ClientQuery {
   Message(ClientMessage),
   Poll,
   ListUsers,
+  /// authentication handshake, see the `auth` module
+  Auth(AuthMessage),
+}
+
+/// reply to a `ClientQuery::Register`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Registered {
+  pub id: ClientId,
+  /// secret used to authenticate, see the `auth` module
+  pub secret: [u8; 16],
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -124,6 +172,76 @@ pub enum ServerMessage {
     clients: HashMap<ClientId, String>,
   },
   Message(FullyQualifiedMessage),
+  /// Withdrawal of a server, or of some of its clients.
+  /// The route is the same as in announces, its first element is the withdrawn server.
+  Withdraw {
+    route: Vec<ServerId>,
+    /// the clients that went away, or the whole server if empty
+    clients: Vec<ClientId>,
+  },
+  /// A message could not be delivered, sent back to the server of its source.
+  Failure {
+    src: ClientId,
+    srcsrv: ServerId,
+    error: DelayedError,
+    /// servers the failure went through, starting with the one that dropped the message
+    path: Vec<ServerId>,
+  },
+  /// A message forwarded by a server that is not its source, which sends it as `Message`.
+  Relay {
+    message: FullyQualifiedMessage,
+    /// servers that forwarded the message, so that loops can be detected
+    path: Vec<ServerId>,
+  },
+  /// An announce with the cost of each link of the route, the first one is between the first two
+  /// servers, and the last one between the last server and us. Links without a cost count as 1.
+  WeightedAnnounce {
+    route: Vec<ServerId>,
+    clients: HashMap<ClientId, String>,
+    metrics: Vec<u32>,
+  },
+  /// The link between two servers went down, the servers themselves might still be reachable
+  /// through other links.
+  Unlink {
+    a: ServerId,
+    b: ServerId,
+  },
+}
+
+/// messages exchanged between servers, see the `peers` module
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PeerMessage {
+  Hello {
+    server: ServerId,
+    nonce: [u8; 8],
+  },
+  Nonce {
+    server: ServerId,
+    nonce: [u8; 8],
+  },
+  Auth {
+    server: ServerId,
+    response: [u8; 16],
+  },
+  /// a server message, authenticated with the session key
+  Message {
+    src: ServerId,
+    seqid: u128,
+    message: ServerMessage,
+    mac: [u8; 16],
+  },
+  /// measures the round-trip time of a link, answered with a `Pong` with the same nonce, both
+  /// are authenticated with the session key
+  Ping {
+    server: ServerId,
+    nonce: [u8; 8],
+    mac: [u8; 16],
+  },
+  Pong {
+    server: ServerId,
+    nonce: [u8; 8],
+    mac: [u8; 16],
+  },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -131,6 +249,8 @@ pub enum ClientError {
   UnknownClient,  // client is unknown
   BoxFull(ClientId),
   InternalError,
+  /// the query could not be decoded, with the reason
+  Malformed(String),
 }
 
 impl std::fmt::Display for ClientError {
@@ -139,6 +259,7 @@ impl std::fmt::Display for ClientError {
       ClientError::BoxFull(clientid) => write!(f, "BoxFull({})", clientid),
       ClientError::InternalError => "InternalError".fmt(f),
       ClientError::UnknownClient => "UnknownClient".fmt(f),
+      ClientError::Malformed(reason) => write!(f, "Malformed({})", reason),
     }
   }
 }
@@ -165,6 +286,8 @@ pub enum ClientReply {
   Delayed,
   /// send to an external server
   Transfer(ServerId, ServerMessage),
+  /// sent to an external server with the `Transfer` of a previous recipient
+  Grouped(ServerId),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -177,6 +300,8 @@ pub enum ClientPollReply {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum DelayedError {
   UnknownRecipient(ClientId),
+  /// the message looped between servers, or went through too many of them
+  Unreachable(ClientId),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -190,4 +315,6 @@ pub enum ServerReply {
   Outgoing(Vec<Outgoing<FullyQualifiedMessage>>),
   EmptyRoute,
   Error(String),
+  /// other server messages to send, such as relayed messages and failures
+  Relay(Vec<Outgoing<ServerMessage>>),
 }
@@ -0,0 +1,570 @@
+/* Middleware around message servers.
+
+  A `Layer` intercepts the calls to a `DynMessageServer`: each of its methods receives the inner
+  server and the arguments of the call, and forwards the call by default. Wrapping a server in a
+  layer gives a new server, so layers compose, the last one added being the first one called:
+
+    let server = DynAdapter::boxed(reference::Server::new(checker, id))
+      .layer(Denylist::default())
+      .layer(Latency::default())
+      .layer(Tracing);
+
+  Layers call the inner server through `&dyn DynMessageServer`, so a `MessageServer` must go
+  through a `DynAdapter` before it can be wrapped. `Layered` does not implement `MessageServer`
+  itself: its generic `handle_sequenced_message` could not be intercepted, and would bypass layers
+  such as `Denylist`.
+
+  The server binary adds layers with `--layer`.
+*/
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::core::DynMessageServer;
+use crate::messages::{
+  ClientError, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
+  ServerId, ServerMessage, ServerReply,
+};
+
+/// intercepts the calls to a server, the default methods call `inner`
+#[async_trait]
+pub trait Layer: Send + Sync {
+  fn group_name(&self, inner: &dyn DynMessageServer) -> &'static str {
+    inner.group_name()
+  }
+
+  async fn register_local_client(
+    &self,
+    inner: &dyn DynMessageServer,
+    src_ip: IpAddr,
+    name: String,
+  ) -> Option<ClientId> {
+    inner.register_local_client(src_ip, name).await
+  }
+
+  async fn list_users(&self, inner: &dyn DynMessageServer) -> HashMap<ClientId, String> {
+    inner.list_users().await
+  }
+
+  async fn handle_sequenced_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    msg: Sequence<ClientQuery>,
+  ) -> Result<ClientQuery, ClientError> {
+    inner.handle_sequenced_message(msg).await
+  }
+
+  async fn client_poll(&self, inner: &dyn DynMessageServer, client: ClientId) -> ClientPollReply {
+    inner.client_poll(client).await
+  }
+
+  async fn handle_client_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    src: ClientId,
+    msg: ClientMessage,
+  ) -> Vec<ClientReply> {
+    inner.handle_client_message(src, msg).await
+  }
+
+  async fn handle_server_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    msg: ServerMessage,
+  ) -> ServerReply {
+    inner.handle_server_message(msg).await
+  }
+
+  async fn route_to(
+    &self,
+    inner: &dyn DynMessageServer,
+    destination: ServerId,
+  ) -> Option<Vec<ServerId>> {
+    inner.route_to(destination).await
+  }
+
+  async fn tick(&self, inner: &dyn DynMessageServer) {
+    inner.tick().await
+  }
+
+  async fn topology_dot(&self, inner: &dyn DynMessageServer) -> Option<String> {
+    inner.topology_dot().await
+  }
+}
+
+/// a server wrapped in a layer, it is a `DynMessageServer` but not a `MessageServer`
+pub struct Layered<L, S> {
+  layer: L,
+  inner: S,
+}
+
+impl<L, S> Layered<L, S> {
+  pub fn new(layer: L, inner: S) -> Self {
+    Layered { layer, inner }
+  }
+
+  pub fn inner(&self) -> &S {
+    &self.inner
+  }
+}
+
+/// wraps servers in layers, `MessageServer`s must be wrapped in a `DynAdapter` first
+pub trait LayerExt: DynMessageServer + Sized {
+  fn layer<L: Layer>(self, layer: L) -> Layered<L, Self> {
+    Layered::new(layer, self)
+  }
+}
+
+impl<S: DynMessageServer> LayerExt for S {}
+
+#[async_trait]
+impl<L: Layer, S: DynMessageServer> DynMessageServer for Layered<L, S> {
+  fn group_name(&self) -> &'static str {
+    self.layer.group_name(&self.inner)
+  }
+
+  async fn register_local_client(&self, src_ip: IpAddr, name: String) -> Option<ClientId> {
+    self
+      .layer
+      .register_local_client(&self.inner, src_ip, name)
+      .await
+  }
+
+  async fn list_users(&self) -> HashMap<ClientId, String> {
+    self.layer.list_users(&self.inner).await
+  }
+
+  async fn handle_sequenced_message(
+    &self,
+    msg: Sequence<ClientQuery>,
+  ) -> Result<ClientQuery, ClientError> {
+    self.layer.handle_sequenced_message(&self.inner, msg).await
+  }
+
+  async fn client_poll(&self, client: ClientId) -> ClientPollReply {
+    self.layer.client_poll(&self.inner, client).await
+  }
+
+  async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply> {
+    self
+      .layer
+      .handle_client_message(&self.inner, src, msg)
+      .await
+  }
+
+  async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply {
+    self.layer.handle_server_message(&self.inner, msg).await
+  }
+
+  async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>> {
+    self.layer.route_to(&self.inner, destination).await
+  }
+
+  async fn tick(&self) {
+    self.layer.tick(&self.inner).await
+  }
+
+  async fn topology_dot(&self) -> Option<String> {
+    self.layer.topology_dot(&self.inner).await
+  }
+}
+
+/// logs every call and its result, at the debug level
+#[derive(Clone, Copy, Default)]
+pub struct Tracing;
+
+#[async_trait]
+impl Layer for Tracing {
+  async fn register_local_client(
+    &self,
+    inner: &dyn DynMessageServer,
+    src_ip: IpAddr,
+    name: String,
+  ) -> Option<ClientId> {
+    let call = format!("register_local_client({}, {:?})", src_ip, name);
+    let r = inner.register_local_client(src_ip, name).await;
+    log::debug!("{} -> {:?}", call, r);
+    r
+  }
+
+  async fn list_users(&self, inner: &dyn DynMessageServer) -> HashMap<ClientId, String> {
+    let r = inner.list_users().await;
+    log::debug!("list_users() -> {} users", r.len());
+    r
+  }
+
+  async fn handle_sequenced_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    msg: Sequence<ClientQuery>,
+  ) -> Result<ClientQuery, ClientError> {
+    let call = format!("handle_sequenced_message({:?})", msg);
+    let r = inner.handle_sequenced_message(msg).await;
+    log::debug!("{} -> {:?}", call, r);
+    r
+  }
+
+  async fn client_poll(&self, inner: &dyn DynMessageServer, client: ClientId) -> ClientPollReply {
+    let r = inner.client_poll(client).await;
+    log::debug!("client_poll({}) -> {:?}", client, r);
+    r
+  }
+
+  async fn handle_client_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    src: ClientId,
+    msg: ClientMessage,
+  ) -> Vec<ClientReply> {
+    let call = format!("handle_client_message({}, {:?})", src, msg);
+    let r = inner.handle_client_message(src, msg).await;
+    log::debug!("{} -> {:?}", call, r);
+    r
+  }
+
+  async fn handle_server_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    msg: ServerMessage,
+  ) -> ServerReply {
+    let call = format!("handle_server_message({:?})", msg);
+    let r = inner.handle_server_message(msg).await;
+    log::debug!("{} -> {:?}", call, r);
+    r
+  }
+
+  async fn route_to(
+    &self,
+    inner: &dyn DynMessageServer,
+    destination: ServerId,
+  ) -> Option<Vec<ServerId>> {
+    let r = inner.route_to(destination).await;
+    log::debug!("route_to({}) -> {:?}", destination, r);
+    r
+  }
+}
+
+/// latencies of the calls to a method, in buckets of powers of two microseconds
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+  /// the bucket `i` counts the calls that took less than 2^i µs, and not less than 2^(i-1) µs
+  buckets: Vec<u64>,
+  count: u64,
+  total: Duration,
+  max: Duration,
+}
+
+impl Histogram {
+  pub fn record(&mut self, latency: Duration) {
+    let micros = latency.as_micros();
+    let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+    if self.buckets.len() <= bucket {
+      self.buckets.resize(bucket + 1, 0);
+    }
+    self.buckets[bucket] += 1;
+    self.count += 1;
+    self.total += latency;
+    self.max = self.max.max(latency);
+  }
+
+  pub fn count(&self) -> u64 {
+    self.count
+  }
+
+  pub fn max(&self) -> Duration {
+    self.max
+  }
+
+  pub fn mean(&self) -> Option<Duration> {
+    let count = u32::try_from(self.count).ok().filter(|c| *c > 0)?;
+    Some(self.total / count)
+  }
+
+  /// the non empty buckets, as (upper bound, count)
+  pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+    self
+      .buckets
+      .iter()
+      .enumerate()
+      .filter(|(_, count)| **count > 0)
+      .map(|(i, count)| (Duration::from_micros(1 << i), *count))
+  }
+
+  /// an upper bound of the `q` quantile, `q` being between 0 and 1
+  pub fn quantile(&self, q: f64) -> Option<Duration> {
+    if self.count == 0 {
+      return None;
+    }
+    let rank = ((self.count as f64 * q).ceil() as u64).max(1);
+    let mut seen = 0;
+    self.buckets().find_map(|(bound, count)| {
+      seen += count;
+      (seen >= rank).then_some(bound)
+    })
+  }
+}
+
+/// Measures the latency of every method. The clones share the same histograms, so that they can
+/// be read while the layer is in use.
+#[derive(Clone, Default)]
+pub struct Latency {
+  histograms: Arc<Mutex<BTreeMap<&'static str, Histogram>>>,
+}
+
+impl Latency {
+  /// the histograms of the methods that were called
+  pub fn histograms(&self) -> BTreeMap<&'static str, Histogram> {
+    self.histograms.lock().unwrap().clone()
+  }
+
+  async fn time<F: Future>(&self, method: &'static str, call: F) -> F::Output {
+    let start = Instant::now();
+    let r = call.await;
+    let latency = start.elapsed();
+    self
+      .histograms
+      .lock()
+      .unwrap()
+      .entry(method)
+      .or_default()
+      .record(latency);
+    r
+  }
+}
+
+#[async_trait]
+impl Layer for Latency {
+  async fn register_local_client(
+    &self,
+    inner: &dyn DynMessageServer,
+    src_ip: IpAddr,
+    name: String,
+  ) -> Option<ClientId> {
+    let call = inner.register_local_client(src_ip, name);
+    self.time("register_local_client", call).await
+  }
+
+  async fn list_users(&self, inner: &dyn DynMessageServer) -> HashMap<ClientId, String> {
+    self.time("list_users", inner.list_users()).await
+  }
+
+  async fn handle_sequenced_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    msg: Sequence<ClientQuery>,
+  ) -> Result<ClientQuery, ClientError> {
+    let call = inner.handle_sequenced_message(msg);
+    self.time("handle_sequenced_message", call).await
+  }
+
+  async fn client_poll(&self, inner: &dyn DynMessageServer, client: ClientId) -> ClientPollReply {
+    self.time("client_poll", inner.client_poll(client)).await
+  }
+
+  async fn handle_client_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    src: ClientId,
+    msg: ClientMessage,
+  ) -> Vec<ClientReply> {
+    let call = inner.handle_client_message(src, msg);
+    self.time("handle_client_message", call).await
+  }
+
+  async fn handle_server_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    msg: ServerMessage,
+  ) -> ServerReply {
+    let call = inner.handle_server_message(msg);
+    self.time("handle_server_message", call).await
+  }
+
+  async fn route_to(
+    &self,
+    inner: &dyn DynMessageServer,
+    destination: ServerId,
+  ) -> Option<Vec<ServerId>> {
+    self.time("route_to", inner.route_to(destination)).await
+  }
+
+  async fn tick(&self, inner: &dyn DynMessageServer) {
+    self.time("tick", inner.tick()).await
+  }
+
+  async fn topology_dot(&self, inner: &dyn DynMessageServer) -> Option<String> {
+    self.time("topology_dot", inner.topology_dot()).await
+  }
+}
+
+/// Rejects the queries, messages and polls of banned clients, as if they were unknown.
+/// The clones share the same list.
+#[derive(Clone, Default)]
+pub struct Denylist {
+  denied: Arc<RwLock<HashSet<ClientId>>>,
+}
+
+impl Denylist {
+  pub fn deny(&self, client: ClientId) {
+    self.denied.write().unwrap().insert(client);
+  }
+
+  /// returns false if the client was not denied
+  pub fn allow(&self, client: ClientId) -> bool {
+    self.denied.write().unwrap().remove(&client)
+  }
+
+  pub fn is_denied(&self, client: &ClientId) -> bool {
+    self.denied.read().unwrap().contains(client)
+  }
+}
+
+#[async_trait]
+impl Layer for Denylist {
+  async fn handle_sequenced_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    msg: Sequence<ClientQuery>,
+  ) -> Result<ClientQuery, ClientError> {
+    if self.is_denied(&msg.src) {
+      return Err(ClientError::UnknownClient);
+    }
+    inner.handle_sequenced_message(msg).await
+  }
+
+  async fn client_poll(&self, inner: &dyn DynMessageServer, client: ClientId) -> ClientPollReply {
+    if self.is_denied(&client) {
+      return ClientPollReply::Nothing;
+    }
+    inner.client_poll(client).await
+  }
+
+  async fn handle_client_message(
+    &self,
+    inner: &dyn DynMessageServer,
+    src: ClientId,
+    msg: ClientMessage,
+  ) -> Vec<ClientReply> {
+    if self.is_denied(&src) {
+      return vec![ClientReply::Error(ClientError::UnknownClient)];
+    }
+    inner.handle_client_message(src, msg).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::{DefaultChecker, DynAdapter, MessageServer};
+  use crate::solutions::reference::Server;
+
+  fn server() -> Box<dyn DynMessageServer> {
+    let server: Server<DefaultChecker> =
+      Server::new(DefaultChecker::default(), ServerId::default());
+    DynAdapter::boxed(server)
+  }
+
+  fn localhost() -> IpAddr {
+    "127.0.0.1".parse().unwrap()
+  }
+
+  fn text(dest: ClientId) -> ClientMessage {
+    ClientMessage::Text {
+      dest,
+      content: "hello".to_string(),
+    }
+  }
+
+  #[test]
+  fn denylist() {
+    async_std::task::block_on(async {
+      let denylist = Denylist::default();
+      let server = server().layer(denylist.clone());
+      let c1 = server
+        .register_local_client(localhost(), "c1".into())
+        .await
+        .unwrap();
+      let c2 = server
+        .register_local_client(localhost(), "c2".into())
+        .await
+        .unwrap();
+      denylist.deny(c1);
+      let r = server.handle_client_message(c1, text(c2)).await;
+      assert_eq!(r, vec![ClientReply::Error(ClientError::UnknownClient)]);
+      let query = Sequence {
+        seqid: 1,
+        src: c1,
+        content: ClientQuery::Poll,
+      };
+      let r = server.handle_sequenced_message(query).await;
+      assert_eq!(r, Err(ClientError::UnknownClient));
+
+      // the denied client can still receive messages, but not read them
+      let r = server.handle_client_message(c2, text(c1)).await;
+      assert_eq!(r, vec![ClientReply::Delivered]);
+      assert_eq!(server.client_poll(c1).await, ClientPollReply::Nothing);
+      assert!(denylist.allow(c1));
+      assert!(!denylist.allow(c1));
+      assert!(matches!(
+        server.client_poll(c1).await,
+        ClientPollReply::Message { src, .. } if src == c2
+      ));
+    });
+  }
+
+  #[test]
+  fn latency() {
+    async_std::task::block_on(async {
+      let latency = Latency::default();
+      let server = server().layer(latency.clone()).layer(Tracing);
+      assert_eq!(server.group_name(), "reference");
+      let c1 = server
+        .register_local_client(localhost(), "c1".into())
+        .await
+        .unwrap();
+      server.handle_client_message(c1, text(c1)).await;
+      server.client_poll(c1).await;
+      server.client_poll(c1).await;
+      let histograms = latency.histograms();
+      let names: Vec<_> = histograms.keys().copied().collect();
+      assert_eq!(
+        names,
+        vec![
+          "client_poll",
+          "handle_client_message",
+          "register_local_client"
+        ]
+      );
+      let polls = &histograms["client_poll"];
+      assert_eq!(polls.count(), 2);
+      assert_eq!(polls.buckets().map(|(_, n)| n).sum::<u64>(), 2);
+      assert!(polls.quantile(1.0).unwrap() > polls.max());
+    });
+  }
+
+  #[test]
+  fn histogram() {
+    let mut histogram = Histogram::default();
+    assert_eq!(histogram.mean(), None);
+    assert_eq!(histogram.quantile(0.5), None);
+    for micros in [0, 1, 3, 3, 100] {
+      histogram.record(Duration::from_micros(micros));
+    }
+    let buckets: Vec<_> = histogram.buckets().collect();
+    let bound = Duration::from_micros;
+    assert_eq!(
+      buckets,
+      vec![(bound(1), 1), (bound(2), 1), (bound(4), 2), (bound(128), 1)]
+    );
+    assert_eq!(histogram.count(), 5);
+    assert_eq!(histogram.mean(), Some(bound(107) / 5));
+    assert_eq!(histogram.max(), bound(100));
+    assert_eq!(histogram.quantile(0.5), Some(bound(4)));
+    assert_eq!(histogram.quantile(0.0), Some(bound(1)));
+    assert_eq!(histogram.quantile(1.0), Some(bound(128)));
+  }
+}
@@ -1,67 +1,341 @@
 use std::{collections::HashMap, io::Read};
 
-use byteorder::{LittleEndian, ReadBytesExt};
 use uuid::Uuid;
 
+use super::serde::{from_reader_with_limits, Deserializer};
 use crate::messages::{
-  AuthMessage, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
-  ServerId, ServerMessage,
+  AuthMessage, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, PeerMessage,
+  Registered, Sequence, ServerId, ServerMessage,
 };
 
+/// Limits enforced while decoding, so that a small hostile message can't make us allocate
+/// huge amounts of memory or recurse forever. The decoding functions use the default limits, the
+/// `*_with` variants and `with_limits` take other values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeLimits {
+  /// maximum size of a string or byte array
+  pub max_string_bytes: usize,
+  /// maximum number of entries in a sequence or map
+  pub max_collection_entries: usize,
+  /// maximum nesting of enums, structs and collections
+  pub max_nesting: usize,
+  /// maximum number of bytes read for a single message
+  pub max_total_bytes: usize,
+}
+
+impl Default for DecodeLimits {
+  fn default() -> Self {
+    DecodeLimits {
+      max_string_bytes: 1 << 16,
+      max_collection_entries: 1 << 16,
+      max_nesting: 32,
+      max_total_bytes: 1 << 24,
+    }
+  }
+}
+
+/// the limit that was exceeded, see `DecodeLimits`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+  StringBytes,
+  CollectionEntries,
+  Nesting,
+  TotalBytes,
+}
+
+/// Decoding errors, with the offset in the input at which they were detected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+  /// the input ended before the message was complete
+  Truncated { offset: usize },
+  /// unknown enum variant, or invalid number prefix
+  UnknownTag { offset: usize, tag: u8 },
+  /// the string starting at the given offset is not valid UTF-8
+  InvalidUtf8 { offset: usize },
+  /// a number was not encoded with the shortest possible encoding
+  NonCanonicalVarint { offset: usize },
+  /// the message was followed by unexpected bytes
+  TrailingBytes { offset: usize },
+  /// see `DecodeLimits`
+  LimitExceeded { offset: usize, limit: Limit },
+  /// error from the underlying reader
+  Io {
+    offset: usize,
+    kind: std::io::ErrorKind,
+  },
+  /// the content is not valid for the expected type
+  Invalid { offset: usize, message: String },
+}
+
+impl DecodeError {
+  pub fn offset(&self) -> usize {
+    match self {
+      DecodeError::Truncated { offset }
+      | DecodeError::UnknownTag { offset, .. }
+      | DecodeError::InvalidUtf8 { offset }
+      | DecodeError::NonCanonicalVarint { offset }
+      | DecodeError::TrailingBytes { offset }
+      | DecodeError::LimitExceeded { offset, .. }
+      | DecodeError::Io { offset, .. }
+      | DecodeError::Invalid { offset, .. } => *offset,
+    }
+  }
+
+  /// a short name for the kind of error, suitable for statistics
+  pub fn kind(&self) -> &'static str {
+    match self {
+      DecodeError::Truncated { .. } => "truncated",
+      DecodeError::UnknownTag { .. } => "unknown_tag",
+      DecodeError::InvalidUtf8 { .. } => "invalid_utf8",
+      DecodeError::NonCanonicalVarint { .. } => "non_canonical_varint",
+      DecodeError::TrailingBytes { .. } => "trailing_bytes",
+      DecodeError::LimitExceeded { .. } => "limit_exceeded",
+      DecodeError::Io { .. } => "io",
+      DecodeError::Invalid { .. } => "invalid",
+    }
+  }
+
+  /// moves the error offset, for errors in a message that was decoded in several parts
+  pub fn shifted(mut self, delta: usize) -> Self {
+    match &mut self {
+      DecodeError::Truncated { offset }
+      | DecodeError::UnknownTag { offset, .. }
+      | DecodeError::InvalidUtf8 { offset }
+      | DecodeError::NonCanonicalVarint { offset }
+      | DecodeError::TrailingBytes { offset }
+      | DecodeError::LimitExceeded { offset, .. }
+      | DecodeError::Io { offset, .. }
+      | DecodeError::Invalid { offset, .. } => *offset += delta,
+    }
+    self
+  }
+}
+
+impl std::fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      DecodeError::Truncated { offset } => write!(f, "truncated input at offset {}", offset),
+      DecodeError::UnknownTag { offset, tag } => {
+        write!(f, "unknown tag {} at offset {}", tag, offset)
+      }
+      DecodeError::InvalidUtf8 { offset } => write!(f, "invalid UTF-8 at offset {}", offset),
+      DecodeError::NonCanonicalVarint { offset } => {
+        write!(f, "non canonical number at offset {}", offset)
+      }
+      DecodeError::TrailingBytes { offset } => write!(f, "trailing bytes at offset {}", offset),
+      DecodeError::LimitExceeded { offset, limit } => {
+        write!(f, "limit {:?} exceeded at offset {}", limit, offset)
+      }
+      DecodeError::Io { offset, kind } => write!(f, "IO error {:?} at offset {}", kind, offset),
+      DecodeError::Invalid { offset, message } => {
+        write!(f, "invalid content at offset {}: {}", offset, message)
+      }
+    }
+  }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl serde::de::Error for DecodeError {
+  // the offset is set by the deserializer, see `Deserializer::deserialize`
+  fn custom<T: std::fmt::Display>(msg: T) -> Self {
+    DecodeError::Invalid {
+      offset: 0,
+      message: msg.to_string(),
+    }
+  }
+}
+
+// all these functions are thin wrappers around the deserializer from the `serde` module
+
+/// decodes any message, using custom limits
+pub fn with_limits<X, R: Read>(rd: &mut R, limits: DecodeLimits) -> Result<X, DecodeError>
+where
+  X: serde::de::DeserializeOwned,
+{
+  from_reader_with_limits(rd, limits)
+}
+
+/// Decodes a complete message, such as a datagram, in strict mode:
+///  * numbers must use their shortest encoding,
+///  * there must be no bytes left once the message is decoded.
+///
+/// This guarantees that a message has a single valid encoding.
+pub fn strict<X>(buf: &[u8], limits: DecodeLimits) -> Result<X, DecodeError>
+where
+  X: serde::de::DeserializeOwned,
+{
+  let mut de = Deserializer::with_limits(buf, limits).canonical();
+  let out = de.deserialize()?;
+  if de.consumed() != buf.len() {
+    return Err(DecodeError::TrailingBytes {
+      offset: de.consumed(),
+    });
+  }
+  Ok(out)
+}
+
 // look at the README.md for guidance on writing this function
-pub fn u128<R: Read>(rd: &mut R) -> anyhow::Result<u128> {
-  todo!()
+pub fn u128<R: Read>(rd: &mut R) -> Result<u128, DecodeError> {
+  u128_with(rd, DecodeLimits::default())
+}
+
+fn uuid<R: Read>(rd: &mut R, limits: DecodeLimits) -> Result<Uuid, DecodeError> {
+  from_reader_with_limits(rd, limits)
+}
+
+pub fn clientid<R: Read>(rd: &mut R) -> Result<ClientId, DecodeError> {
+  clientid_with(rd, DecodeLimits::default())
+}
+
+pub fn serverid<R: Read>(rd: &mut R) -> Result<ServerId, DecodeError> {
+  serverid_with(rd, DecodeLimits::default())
+}
+
+pub fn string<R: Read>(rd: &mut R) -> Result<String, DecodeError> {
+  string_with(rd, DecodeLimits::default())
+}
+
+pub fn auth<R: Read>(rd: &mut R) -> Result<AuthMessage, DecodeError> {
+  auth_with(rd, DecodeLimits::default())
+}
+
+pub fn client<R: Read>(rd: &mut R) -> Result<ClientMessage, DecodeError> {
+  client_with(rd, DecodeLimits::default())
+}
+
+pub fn client_replies<R: Read>(rd: &mut R) -> Result<Vec<ClientReply>, DecodeError> {
+  client_replies_with(rd, DecodeLimits::default())
+}
+
+pub fn client_poll_reply<R: Read>(rd: &mut R) -> Result<ClientPollReply, DecodeError> {
+  client_poll_reply_with(rd, DecodeLimits::default())
+}
+
+pub fn server<R: Read>(rd: &mut R) -> Result<ServerMessage, DecodeError> {
+  server_with(rd, DecodeLimits::default())
+}
+
+pub fn peer<R: Read>(rd: &mut R) -> Result<PeerMessage, DecodeError> {
+  peer_with(rd, DecodeLimits::default())
+}
+
+pub fn userlist<R: Read>(rd: &mut R) -> Result<HashMap<ClientId, String>, DecodeError> {
+  userlist_with(rd, DecodeLimits::default())
+}
+
+pub fn client_query<R: Read>(rd: &mut R) -> Result<ClientQuery, DecodeError> {
+  client_query_with(rd, DecodeLimits::default())
+}
+
+pub fn registered<R: Read>(rd: &mut R) -> Result<Registered, DecodeError> {
+  registered_with(rd, DecodeLimits::default())
+}
+
+pub fn sequence<X, R: Read, DEC>(rd: &mut R, d: DEC) -> Result<Sequence<X>, DecodeError>
+where
+  DEC: FnOnce(&mut R) -> Result<X, DecodeError>,
+{
+  sequence_with(rd, DecodeLimits::default(), |rd, _| d(rd))
+}
+
+// the same functions, with custom limits
+
+pub fn u128_with<R: Read>(rd: &mut R, limits: DecodeLimits) -> Result<u128, DecodeError> {
+  from_reader_with_limits(rd, limits)
+}
+
+pub fn clientid_with<R: Read>(rd: &mut R, limits: DecodeLimits) -> Result<ClientId, DecodeError> {
+  uuid(rd, limits).map(ClientId)
 }
 
-fn uuid<R: Read>(rd: &mut R) -> anyhow::Result<Uuid> {
-  todo!()
+pub fn serverid_with<R: Read>(rd: &mut R, limits: DecodeLimits) -> Result<ServerId, DecodeError> {
+  uuid(rd, limits).map(ServerId)
 }
 
-// hint: reuse uuid
-pub fn clientid<R: Read>(rd: &mut R) -> anyhow::Result<ClientId> {
-  todo!()
+pub fn string_with<R: Read>(rd: &mut R, limits: DecodeLimits) -> Result<String, DecodeError> {
+  from_reader_with_limits(rd, limits)
 }
 
-// hint: reuse uuid
-pub fn serverid<R: Read>(rd: &mut R) -> anyhow::Result<ServerId> {
-  todo!()
+pub fn auth_with<R: Read>(rd: &mut R, limits: DecodeLimits) -> Result<AuthMessage, DecodeError> {
+  from_reader_with_limits(rd, limits)
 }
 
-pub fn string<R: Read>(rd: &mut R) -> anyhow::Result<String> {
-  todo!()
+pub fn client_with<R: Read>(
+  rd: &mut R,
+  limits: DecodeLimits,
+) -> Result<ClientMessage, DecodeError> {
+  from_reader_with_limits(rd, limits)
 }
 
-pub fn auth<R: Read>(rd: &mut R) -> anyhow::Result<AuthMessage> {
-  todo!()
+pub fn client_replies_with<R: Read>(
+  rd: &mut R,
+  limits: DecodeLimits,
+) -> Result<Vec<ClientReply>, DecodeError> {
+  from_reader_with_limits(rd, limits)
 }
 
-pub fn client<R: Read>(rd: &mut R) -> anyhow::Result<ClientMessage> {
-  todo!()
+pub fn client_poll_reply_with<R: Read>(
+  rd: &mut R,
+  limits: DecodeLimits,
+) -> Result<ClientPollReply, DecodeError> {
+  from_reader_with_limits(rd, limits)
 }
 
-pub fn client_replies<R: Read>(rd: &mut R) -> anyhow::Result<Vec<ClientReply>> {
-  todo!()
+pub fn server_with<R: Read>(
+  rd: &mut R,
+  limits: DecodeLimits,
+) -> Result<ServerMessage, DecodeError> {
+  from_reader_with_limits(rd, limits)
 }
 
-pub fn client_poll_reply<R: Read>(rd: &mut R) -> anyhow::Result<ClientPollReply> {
-  todo!()
+pub fn peer_with<R: Read>(rd: &mut R, limits: DecodeLimits) -> Result<PeerMessage, DecodeError> {
+  from_reader_with_limits(rd, limits)
 }
 
-pub fn server<R: Read>(rd: &mut R) -> anyhow::Result<ServerMessage> {
-  todo!()
+pub fn userlist_with<R: Read>(
+  rd: &mut R,
+  limits: DecodeLimits,
+) -> Result<HashMap<ClientId, String>, DecodeError> {
+  from_reader_with_limits(rd, limits)
 }
 
-pub fn userlist<R: Read>(rd: &mut R) -> anyhow::Result<HashMap<ClientId, String>> {
-  todo!()
+pub fn client_query_with<R: Read>(
+  rd: &mut R,
+  limits: DecodeLimits,
+) -> Result<ClientQuery, DecodeError> {
+  from_reader_with_limits(rd, limits)
 }
 
-pub fn client_query<R: Read>(rd: &mut R) -> anyhow::Result<ClientQuery> {
-  todo!()
+pub fn registered_with<R: Read>(
+  rd: &mut R,
+  limits: DecodeLimits,
+) -> Result<Registered, DecodeError> {
+  from_reader_with_limits(rd, limits)
 }
 
-pub fn sequence<X, R: Read, DEC>(rd: &mut R, d: DEC) -> anyhow::Result<Sequence<X>>
+/// the content decoder gets the limits that are left once the header is decoded
+pub fn sequence_with<X, R: Read, DEC>(
+  rd: &mut R,
+  limits: DecodeLimits,
+  d: DEC,
+) -> Result<Sequence<X>, DecodeError>
 where
-  DEC: FnOnce(&mut R) -> anyhow::Result<X>,
+  DEC: FnOnce(&mut R, DecodeLimits) -> Result<X, DecodeError>,
 {
-  todo!()
+  let mut de = Deserializer::with_limits(&mut *rd, limits);
+  let seqid = de.deserialize()?;
+  let src = de.deserialize()?;
+  let _stamp: u64 = de.deserialize()?;
+  let header = de.consumed();
+  let remaining = DecodeLimits {
+    max_total_bytes: limits.max_total_bytes.saturating_sub(header),
+    ..limits
+  };
+  let content = d(rd, remaining).map_err(|rr| rr.shifted(header))?;
+  Ok(Sequence {
+    seqid,
+    src,
+    content,
+  })
 }
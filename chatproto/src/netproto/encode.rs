@@ -3,9 +3,10 @@ use std::{collections::HashMap, io::Write};
 use byteorder::{LittleEndian, WriteBytesExt};
 use uuid::Uuid;
 
+use super::serde::to_writer;
 use crate::messages::{
-  AuthMessage, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
-  ServerId, ServerMessage,
+  AuthMessage, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, PeerMessage,
+  Registered, Sequence, ServerId, ServerMessage, SEQUENCE_STAMP,
 };
 
 // look at the README.md for guidance on writing this function
@@ -16,8 +17,18 @@ where
 {
   if m < 251 {
     w.write_u8(m as u8)
+  } else if m < 1 << 16 {
+    w.write_u8(251)?;
+    w.write_u16::<LittleEndian>(m as u16)
+  } else if m < 1 << 32 {
+    w.write_u8(252)?;
+    w.write_u32::<LittleEndian>(m as u32)
+  } else if m < 1 << 64 {
+    w.write_u8(253)?;
+    w.write_u64::<LittleEndian>(m as u64)
   } else {
-    todo!()
+    w.write_u8(254)?;
+    w.write_u128::<LittleEndian>(m)
   }
 }
 
@@ -28,7 +39,9 @@ fn uuid<W>(w: &mut W, m: &Uuid) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  let bytes = m.as_bytes();
+  u128(w, bytes.len() as u128)?;
+  w.write_all(bytes)
 }
 
 // reuse uuid
@@ -36,7 +49,7 @@ pub fn clientid<W>(w: &mut W, m: &ClientId) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  uuid(w, &m.0)
 }
 
 // reuse uuid
@@ -44,7 +57,7 @@ pub fn serverid<W>(w: &mut W, m: &ServerId) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  uuid(w, &m.0)
 }
 
 // strings are encoded as the underlying bytes array
@@ -56,7 +69,9 @@ pub fn string<W>(w: &mut W, m: &str) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  let bytes = m.as_bytes();
+  u128(w, bytes.len() as u128)?;
+  w.write_all(bytes)
 }
 
 /* The following is VERY mechanical, and should be easy once the general principle is understood
@@ -84,6 +99,7 @@ where
    Test::B(8) is encoded as [1, 8]
    Test::C(3, 17) is encoded as [2, 3, 17]
 
+  As all these types implement Serialize, they are encoded by the serializer in the `serde` module.
  */
 
 pub fn auth<W>(w: &mut W, m: &AuthMessage) -> std::io::Result<()>
@@ -112,28 +128,35 @@ pub fn server<W>(w: &mut W, m: &ServerMessage) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  Ok(to_writer(w, m)?)
+}
+
+pub fn peer<W>(w: &mut W, m: &PeerMessage) -> std::io::Result<()>
+where
+  W: Write,
+{
+  Ok(to_writer(w, m)?)
 }
 
 pub fn client<W>(w: &mut W, m: &ClientMessage) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  Ok(to_writer(w, m)?)
 }
 
 pub fn client_replies<W>(w: &mut W, m: &[ClientReply]) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  Ok(to_writer(w, m)?)
 }
 
 pub fn client_poll_reply<W>(w: &mut W, m: &ClientPollReply) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  Ok(to_writer(w, m)?)
 }
 
 // hashmaps are encoded by first writing the size (using u128), then each key and values
@@ -141,14 +164,21 @@ pub fn userlist<W>(w: &mut W, m: &HashMap<ClientId, String>) -> std::io::Result<
 where
   W: Write,
 {
-  todo!()
+  Ok(to_writer(w, m)?)
 }
 
 pub fn client_query<W>(w: &mut W, m: &ClientQuery) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  Ok(to_writer(w, m)?)
+}
+
+pub fn registered<W>(w: &mut W, m: &Registered) -> std::io::Result<()>
+where
+  W: Write,
+{
+  Ok(to_writer(w, m)?)
 }
 
 pub fn sequence<X, W, ENC>(w: &mut W, m: &Sequence<X>, f: ENC) -> std::io::Result<()>
@@ -157,5 +187,8 @@ where
   X: serde::Serialize,
   ENC: FnOnce(&mut W, &X) -> std::io::Result<()>,
 {
-  todo!()
+  u128(w, m.seqid)?;
+  clientid(w, &m.src)?;
+  u128(w, SEQUENCE_STAMP as u128)?;
+  f(w, &m.content)
 }
@@ -0,0 +1,177 @@
+/* Length delimited frames, so that the protocol can be used over streams (such as TCP).
+
+  Each frame is the length of the payload (encoded like all numbers, see `encode::u128`), followed
+  by the payload, that is a single encoded message.
+
+  Sending waits until the frame is completely written and flushed, so a slow reader slows down the
+  writer instead of having frames pile up in memory.
+*/
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::decode::{self, DecodeError, DecodeLimits};
+use super::{encode, serde::to_bytes};
+
+#[derive(Debug)]
+pub enum FrameError {
+  Io(std::io::Error),
+  /// the frame is larger than `DecodeLimits::max_total_bytes`
+  TooLarge(u128),
+  Decode(DecodeError),
+  Encode(super::serde::Error),
+}
+
+impl std::fmt::Display for FrameError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      FrameError::Io(rr) => rr.fmt(f),
+      FrameError::TooLarge(len) => write!(f, "frame too large ({} bytes)", len),
+      FrameError::Decode(rr) => rr.fmt(f),
+      FrameError::Encode(rr) => rr.fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for FrameError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      FrameError::Io(rr) => Some(rr),
+      FrameError::TooLarge(_) => None,
+      FrameError::Decode(rr) => Some(rr),
+      FrameError::Encode(rr) => Some(rr),
+    }
+  }
+}
+
+impl From<std::io::Error> for FrameError {
+  fn from(value: std::io::Error) -> Self {
+    FrameError::Io(value)
+  }
+}
+
+impl From<DecodeError> for FrameError {
+  fn from(value: DecodeError) -> Self {
+    FrameError::Decode(value)
+  }
+}
+
+impl From<super::serde::Error> for FrameError {
+  fn from(value: super::serde::Error) -> Self {
+    FrameError::Encode(value)
+  }
+}
+
+/// writes a single frame, and flushes the writer
+pub async fn write_frame<W>(w: &mut W, payload: &[u8]) -> std::io::Result<()>
+where
+  W: AsyncWrite + Unpin,
+{
+  // header and payload are written at once
+  let mut buf = Vec::with_capacity(payload.len() + 17);
+  encode::u128(&mut buf, payload.len() as u128)?;
+  buf.extend_from_slice(payload);
+  w.write_all(&buf).await?;
+  w.flush().await
+}
+
+/// reads a single frame, returns None if the stream ends between two frames
+pub async fn read_frame<R>(rd: &mut R, max_size: usize) -> Result<Option<Vec<u8>>, FrameError>
+where
+  R: AsyncRead + Unpin,
+{
+  let mut header = [0u8; 17];
+  if rd.read(&mut header[..1]).await? == 0 {
+    return Ok(None);
+  }
+  let header_len = match header[0] {
+    251 => 3,
+    252 => 5,
+    253 => 9,
+    254 => 17,
+    _ => 1,
+  };
+  rd.read_exact(&mut header[1..header_len]).await?;
+  let len: u128 = decode::strict(&header[..header_len], DecodeLimits::default())?;
+  if len > max_size as u128 {
+    return Err(FrameError::TooLarge(len));
+  }
+  // the buffer grows with the data that is actually received
+  let mut payload = Vec::new();
+  rd.take(len as u64).read_to_end(&mut payload).await?;
+  if payload.len() as u128 != len {
+    return Err(FrameError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+  }
+  Ok(Some(payload))
+}
+
+/// Reads messages from a stream, such as `Sequence<ClientQuery>` or `ServerMessage`
+pub struct FrameReader<R> {
+  rd: BufReader<R>,
+  limits: DecodeLimits,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+  pub fn new(rd: R) -> Self {
+    Self::with_limits(rd, DecodeLimits::default())
+  }
+
+  /// `limits.max_total_bytes` is also the maximum frame size
+  pub fn with_limits(rd: R, limits: DecodeLimits) -> Self {
+    FrameReader {
+      rd: BufReader::new(rd),
+      limits,
+    }
+  }
+
+  /// receives the next message, None means the stream was closed
+  pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>, FrameError> {
+    match self.recv_frame().await? {
+      None => Ok(None),
+      Some(payload) => Ok(Some(decode::strict(&payload, self.limits)?)),
+    }
+  }
+
+  /// receives the payload of the next frame, without decoding it
+  pub async fn recv_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+    read_frame(&mut self.rd, self.limits.max_total_bytes).await
+  }
+
+  pub fn into_inner(self) -> R {
+    self.rd.into_inner()
+  }
+}
+
+/// Writes messages to a stream
+pub struct FrameWriter<W> {
+  w: W,
+  max_size: usize,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+  pub fn new(w: W) -> Self {
+    FrameWriter {
+      w,
+      max_size: DecodeLimits::default().max_total_bytes,
+    }
+  }
+
+  /// refuse to send messages that the other side would not accept
+  pub fn with_limits(w: W, limits: DecodeLimits) -> Self {
+    FrameWriter {
+      w,
+      max_size: limits.max_total_bytes,
+    }
+  }
+
+  pub async fn send<T: Serialize + ?Sized>(&mut self, msg: &T) -> Result<(), FrameError> {
+    let payload = to_bytes(msg)?;
+    if payload.len() > self.max_size {
+      return Err(FrameError::TooLarge(payload.len() as u128));
+    }
+    Ok(write_frame(&mut self.w, &payload).await?)
+  }
+
+  pub fn into_inner(self) -> W {
+    self.w
+  }
+}
@@ -1,5 +1,9 @@
 pub mod decode;
 pub mod encode;
+pub mod frame;
+pub mod serde;
+
+pub use decode::DecodeError;
 
 #[cfg(test)]
 mod test {
@@ -11,6 +15,9 @@ mod test {
 
   use super::decode;
   use super::encode;
+  use super::frame;
+  use super::serde as wire;
+  use super::DecodeError;
 
   fn servermessages() -> Vec<ServerMessage> {
     // large announce
@@ -47,6 +54,19 @@ mod test {
         ],
         content: "World!".into(),
       }),
+      ServerMessage::Withdraw {
+        route: vec![ServerId::default(), ServerId::default()],
+        clients: Vec::new(),
+      },
+      ServerMessage::WeightedAnnounce {
+        route: vec![ServerId::default(), ServerId::default()],
+        clients: HashMap::from([(ClientId::default(), "user 1".to_string())]),
+        metrics: vec![1, 70000],
+      },
+      ServerMessage::Unlink {
+        a: ServerId::default(),
+        b: ServerId::default(),
+      },
     ]
   }
 
@@ -66,6 +86,17 @@ mod test {
           114, 100, 99, 111, 100, 101, 100,
         ],
       ),
+      (
+        ServerMessage::WeightedAnnounce {
+          route: vec![uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into()],
+          clients: HashMap::new(),
+          metrics: vec![42],
+        },
+        vec![
+          5, 1, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 0,
+          1, 42,
+        ],
+      ),
       (
         ServerMessage::Message(FullyQualifiedMessage {
           src: uuid!["50064dda-865d-4070-a843-aaca292cb85e"].into(),
@@ -91,6 +122,61 @@ mod test {
           179, 18, 64, 158, 9, 10, 15, 4, 89, 101, 115, 33,
         ],
       ),
+      (
+        ServerMessage::Withdraw {
+          route: vec![uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into()],
+          clients: vec![uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into()],
+        },
+        vec![
+          2, 1, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 1,
+          16, 39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20,
+        ],
+      ),
+      (
+        ServerMessage::Unlink {
+          a: uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
+          b: uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
+        },
+        vec![
+          6, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 16, 39,
+          41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20,
+        ],
+      ),
+      (
+        ServerMessage::Relay {
+          message: FullyQualifiedMessage {
+            src: uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
+            srcsrv: uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
+            dsts: vec![(
+              uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
+              uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
+            )],
+            content: "hop".into(),
+          },
+          path: vec![uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into()],
+        },
+        vec![
+          4, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 16, 39,
+          41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 1, 16, 115, 32, 55,
+          175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 16, 39, 41, 62, 160, 35,
+          197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 3, 104, 111, 112, 1, 16, 39, 41, 62,
+          160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20,
+        ],
+      ),
+      (
+        ServerMessage::Failure {
+          src: uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
+          srcsrv: uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
+          error: DelayedError::Unreachable(uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into()),
+          path: vec![uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into()],
+        },
+        vec![
+          3, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 16, 39,
+          41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 1, 16, 115, 32, 55,
+          175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 1, 16, 39, 41, 62, 160, 35,
+          197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20,
+        ],
+      ),
     ]
   }
 
@@ -166,7 +252,7 @@ mod test {
   where
     T: Eq + std::fmt::Debug,
     ENC: FnOnce(&mut Cursor<Vec<u8>>, &T) -> std::io::Result<()>,
-    DEC: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<T>,
+    DEC: FnOnce(&mut Cursor<Vec<u8>>) -> Result<T, DecodeError>,
   {
     log::info!("test {:?} <-> {:?}", clear, encoded);
     let mut wr = Cursor::new(Vec::new());
@@ -341,6 +427,36 @@ mod test {
     round_trip(encode::client_query, decode::client_query, &query, &[3]);
   }
 
+  #[test]
+  fn client_query_auth() {
+    let query = ClientQuery::Auth(AuthMessage::Auth {
+      response: [7; 16],
+    });
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &query,
+      &[4, 2, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7],
+    );
+  }
+
+  #[test]
+  fn registered() {
+    let reply = Registered {
+      id: ClientId::from(uuid!("a1a2a3a4-b1b2-c1c2-d1d2-d3d4d5d6d7d8")),
+      secret: [1; 16],
+    };
+    round_trip(
+      encode::registered,
+      decode::registered,
+      &reply,
+      &[
+        16, 161, 162, 163, 164, 177, 178, 193, 194, 209, 210, 211, 212, 213, 214, 215, 216, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+      ],
+    );
+  }
+
   #[test]
   fn string_decode() {
     let mut cursor = Cursor::new([
@@ -368,4 +484,438 @@ mod test {
       encoded,
     );
   }
+
+  fn serde_hardcoded<T>(samples: Vec<(T, Vec<u8>)>)
+  where
+    T: ::serde::Serialize + ::serde::de::DeserializeOwned + Eq + std::fmt::Debug,
+  {
+    for (msg, expected) in samples {
+      assert_eq!(wire::to_bytes(&msg).unwrap(), expected);
+      let decoded: T = wire::from_reader(Cursor::new(expected)).unwrap();
+      assert_eq!(decoded, msg);
+    }
+  }
+
+  #[test]
+  fn serde_server_hardcoded() {
+    serde_hardcoded(server_hardcoded());
+  }
+
+  #[test]
+  fn serde_auth_hardcoded() {
+    serde_hardcoded(auth_hardcoded());
+  }
+
+  #[test]
+  fn serde_client_hardcoded() {
+    serde_hardcoded(client_hardcoded());
+  }
+
+  #[test]
+  fn serde_server_round_trip() {
+    for msg in servermessages() {
+      let decoded: ServerMessage =
+        wire::from_reader(Cursor::new(wire::to_bytes(&msg).unwrap())).unwrap();
+      assert_eq!(decoded, msg);
+    }
+  }
+
+  #[test]
+  fn serde_other_types() {
+    let value: (i32, i64, bool, Option<u16>, Option<char>, f64) =
+      (-5, i64::MIN, true, Some(300), None, 1.5);
+    let encoded = wire::to_bytes(&value).unwrap();
+    assert_eq!(&encoded[..2], &[9, 253]);
+    let decoded: (i32, i64, bool, Option<u16>, Option<char>, f64) =
+      wire::from_reader(Cursor::new(encoded)).unwrap();
+    assert_eq!(decoded, value);
+  }
+
+  fn exceeded<T: std::fmt::Debug>(r: Result<T, DecodeError>) -> Option<decode::Limit> {
+    match r.unwrap_err() {
+      DecodeError::LimitExceeded { limit, .. } => Some(limit),
+      _ => None,
+    }
+  }
+
+  fn hostile_length() -> Vec<u8> {
+    let mut out = vec![254];
+    out.extend_from_slice(&u128::MAX.to_le_bytes());
+    out
+  }
+
+  #[test]
+  fn limits_hostile_string() {
+    let mut cursor = Cursor::new(hostile_length());
+    assert_eq!(
+      exceeded(decode::string(&mut cursor)),
+      Some(decode::Limit::StringBytes)
+    );
+  }
+
+  #[test]
+  fn limits_hostile_collections() {
+    let mut cursor = Cursor::new(hostile_length());
+    assert_eq!(
+      exceeded(decode::userlist(&mut cursor)),
+      Some(decode::Limit::CollectionEntries)
+    );
+    let mut cursor = Cursor::new(hostile_length());
+    assert_eq!(
+      exceeded(decode::client_replies(&mut cursor)),
+      Some(decode::Limit::CollectionEntries)
+    );
+    // an announce with a hostile route length
+    let mut buf = vec![0];
+    buf.extend(hostile_length());
+    let mut cursor = Cursor::new(buf);
+    assert_eq!(
+      exceeded(decode::server(&mut cursor)),
+      Some(decode::Limit::CollectionEntries)
+    );
+  }
+
+  #[test]
+  fn limits_truncated_string() {
+    // the length is within the limits, but the data is missing
+    let mut cursor = Cursor::new([251, 0xff, 0xff, 65, 66]);
+    let rr = decode::string(&mut cursor).unwrap_err();
+    assert_eq!(rr, DecodeError::Truncated { offset: 5 });
+  }
+
+  #[test]
+  fn limits_nesting() {
+    let limits = decode::DecodeLimits {
+      max_nesting: 2,
+      ..Default::default()
+    };
+    let nested: Vec<Vec<Vec<u8>>> = vec![vec![vec![1, 2]]];
+    let encoded = wire::to_bytes(&nested).unwrap();
+    let mut cursor = Cursor::new(encoded.clone());
+    assert_eq!(
+      exceeded(decode::with_limits::<Vec<Vec<Vec<u8>>>, _>(
+        &mut cursor,
+        limits
+      )),
+      Some(decode::Limit::Nesting)
+    );
+    let mut cursor = Cursor::new(encoded);
+    let decoded: Vec<Vec<Vec<u8>>> = decode::with_limits(&mut cursor, Default::default()).unwrap();
+    assert_eq!(decoded, nested);
+  }
+
+  #[test]
+  fn limits_total_bytes() {
+    let limits = decode::DecodeLimits {
+      max_total_bytes: 8192,
+      ..Default::default()
+    };
+    for msg in servermessages() {
+      let encoded = wire::to_bytes(&msg).unwrap();
+      let mut cursor = Cursor::new(encoded.clone());
+      let r = decode::with_limits::<ServerMessage, _>(&mut cursor, limits);
+      if encoded.len() > limits.max_total_bytes {
+        assert_eq!(exceeded(r), Some(decode::Limit::TotalBytes));
+      } else {
+        assert_eq!(r.unwrap(), msg);
+      }
+    }
+  }
+
+  #[test]
+  fn limits_sequence() {
+    let sq = Sequence {
+      seqid: 12,
+      src: ClientId::from(4),
+      content: "Hello world".to_string(),
+    };
+    let mut encoded = Vec::new();
+    encode::sequence(&mut encoded, &sq, |w, s| encode::string(w, s)).unwrap();
+    let decode_with =
+      |limits| decode::sequence_with(&mut Cursor::new(&encoded), limits, decode::string_with);
+    let strings = decode::DecodeLimits {
+      max_string_bytes: 4,
+      ..Default::default()
+    };
+    assert_eq!(
+      exceeded(decode_with(strings)),
+      Some(decode::Limit::StringBytes)
+    );
+    // the header counts towards the total
+    let total = decode::DecodeLimits {
+      max_total_bytes: encoded.len() - 1,
+      ..Default::default()
+    };
+    assert_eq!(
+      exceeded(decode_with(total)),
+      Some(decode::Limit::TotalBytes)
+    );
+    assert_eq!(decode_with(Default::default()), Ok(sq));
+  }
+
+  #[test]
+  fn error_unknown_tag() {
+    let mut cursor = Cursor::new([9]);
+    assert_eq!(
+      decode::client_query(&mut cursor),
+      Err(DecodeError::UnknownTag { offset: 0, tag: 9 })
+    );
+    let mut cursor = Cursor::new([1, 255]);
+    assert_eq!(
+      decode::client_replies(&mut cursor),
+      Err(DecodeError::UnknownTag {
+        offset: 1,
+        tag: 255
+      })
+    );
+  }
+
+  #[test]
+  fn error_invalid_utf8() {
+    let mut buf = Vec::new();
+    encode::client(
+      &mut buf,
+      &ClientMessage::Text {
+        dest: ClientId::from(1),
+        content: "abc".into(),
+      },
+    )
+    .unwrap();
+    // replace the last character with an invalid byte
+    *buf.last_mut().unwrap() = 0xff;
+    let mut cursor = Cursor::new(buf);
+    assert_eq!(
+      decode::client(&mut cursor),
+      Err(DecodeError::InvalidUtf8 { offset: 21 })
+    );
+  }
+
+  #[test]
+  fn error_truncated() {
+    for (_, buf) in server_hardcoded() {
+      for n in 0..buf.len() {
+        let mut cursor = Cursor::new(&buf[..n]);
+        match decode::server(&mut cursor) {
+          Err(DecodeError::Truncated { offset }) => assert_eq!(offset, n),
+          r => panic!("expected truncated error at offset {}, got {:?}", n, r),
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn error_sequence_offset() {
+    let mut buf = Vec::new();
+    encode::sequence(
+      &mut buf,
+      &Sequence {
+        seqid: 1,
+        src: ClientId::from(1),
+        content: ClientQuery::Poll,
+      },
+      encode::client_query,
+    )
+    .unwrap();
+    *buf.last_mut().unwrap() = 12;
+    let mut cursor = Cursor::new(buf);
+    assert_eq!(
+      decode::sequence(&mut cursor, decode::client_query),
+      Err(DecodeError::UnknownTag {
+        offset: 27,
+        tag: 12
+      })
+    );
+  }
+
+  #[test]
+  fn strict_non_canonical() {
+    // 5 can be encoded with any prefix
+    for buf in [
+      vec![251, 5, 0],
+      vec![252, 5, 0, 0, 0],
+      vec![253, 5, 0, 0, 0, 0, 0, 0, 0],
+    ] {
+      assert_eq!(decode::u128(&mut Cursor::new(buf.clone())), Ok(5));
+      assert_eq!(
+        decode::strict::<u128>(&buf, Default::default()),
+        Err(DecodeError::NonCanonicalVarint { offset: 0 })
+      );
+    }
+    assert_eq!(
+      decode::strict::<u128>(&[251, 251, 0], Default::default()),
+      Ok(251)
+    );
+    // the canonical samples are accepted
+    for (msg, buf) in server_hardcoded() {
+      assert_eq!(decode::strict(&buf, Default::default()), Ok(msg));
+    }
+  }
+
+  #[test]
+  fn strict_uuid_length() {
+    let mut buf = vec![0, 17];
+    buf.extend([0; 17]);
+    buf.extend([0]);
+    assert!(matches!(
+      decode::strict::<ClientMessage>(&buf, Default::default()),
+      Err(DecodeError::Invalid { offset: 19, .. })
+    ));
+    // non canonical uuid length prefix
+    let mut buf = vec![0, 251, 16, 0];
+    buf.extend([0; 16]);
+    buf.extend([0]);
+    assert_eq!(
+      decode::strict::<ClientMessage>(&buf, Default::default()),
+      Err(DecodeError::NonCanonicalVarint { offset: 1 })
+    );
+  }
+
+  #[test]
+  fn strict_trailing_bytes() {
+    for (msg, mut buf) in client_hardcoded() {
+      let len = buf.len();
+      buf.push(0);
+      assert_eq!(
+        decode::client(&mut Cursor::new(buf.clone())),
+        Ok(msg.clone())
+      );
+      assert_eq!(
+        decode::strict::<ClientMessage>(&buf, Default::default()),
+        Err(DecodeError::TrailingBytes { offset: len })
+      );
+    }
+  }
+
+  /// a reader that returns at most one byte per read, and is not always ready
+  struct Trickle {
+    data: Vec<u8>,
+    pos: usize,
+    ready: bool,
+  }
+
+  impl futures::io::AsyncRead for Trickle {
+    fn poll_read(
+      mut self: std::pin::Pin<&mut Self>,
+      cx: &mut std::task::Context<'_>,
+      buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+      self.ready = !self.ready;
+      if !self.ready {
+        cx.waker().wake_by_ref();
+        return std::task::Poll::Pending;
+      }
+      if self.pos == self.data.len() || buf.is_empty() {
+        return std::task::Poll::Ready(Ok(0));
+      }
+      buf[0] = self.data[self.pos];
+      self.pos += 1;
+      std::task::Poll::Ready(Ok(1))
+    }
+  }
+
+  fn queries() -> Vec<Sequence<ClientQuery>> {
+    let mut client = crate::client::Client::new(ClientId::from(42));
+    vec![
+      client.sequence(ClientQuery::Register("Bob".into())),
+      client.sequence(ClientQuery::Poll),
+      client.sequence(ClientQuery::Message(ClientMessage::MText {
+        dest: vec![ClientId::from(1), ClientId::from(2)],
+        content: "x".repeat(300),
+      })),
+      client.sequence(ClientQuery::ListUsers),
+    ]
+  }
+
+  #[test]
+  fn frame_partial_reads() {
+    async_std::task::block_on(async {
+      let mut out = frame::FrameWriter::new(futures::io::Cursor::new(Vec::new()));
+      for q in queries() {
+        out.send(&q).await.unwrap();
+      }
+      let messages = servermessages();
+      for m in &messages {
+        out.send(m).await.unwrap();
+      }
+      let data = out.into_inner().into_inner();
+
+      let mut rd = frame::FrameReader::new(Trickle {
+        data,
+        pos: 0,
+        ready: false,
+      });
+      for q in queries() {
+        assert_eq!(rd.recv::<Sequence<ClientQuery>>().await.unwrap(), Some(q));
+      }
+      for m in messages {
+        assert_eq!(rd.recv::<ServerMessage>().await.unwrap(), Some(m));
+      }
+      assert_eq!(rd.recv::<ServerMessage>().await.unwrap(), None);
+    })
+  }
+
+  #[test]
+  fn frame_errors() {
+    async_std::task::block_on(async {
+      // truncated payload
+      let mut data = Vec::new();
+      frame::write_frame(&mut data, &[1, 2, 3]).await.unwrap();
+      data.pop();
+      let mut rd = frame::FrameReader::new(&data[..]);
+      match rd.recv::<Vec<u8>>().await {
+        Err(frame::FrameError::Io(rr)) => {
+          assert_eq!(rr.kind(), std::io::ErrorKind::UnexpectedEof)
+        }
+        r => panic!("expected an IO error, got {:?}", r),
+      }
+
+      // hostile frame size
+      let limits = decode::DecodeLimits {
+        max_total_bytes: 8192,
+        ..Default::default()
+      };
+      let hostile = hostile_length();
+      let mut rd = frame::FrameReader::with_limits(&hostile[..], limits);
+      assert!(matches!(
+        rd.recv::<Vec<u8>>().await,
+        Err(frame::FrameError::TooLarge(u128::MAX))
+      ));
+
+      // too large to be sent
+      let mut out = frame::FrameWriter::with_limits(Vec::new(), limits);
+      assert!(matches!(
+        out.send(&servermessages()[2]).await,
+        Err(frame::FrameError::TooLarge(_))
+      ));
+      assert!(out.into_inner().is_empty());
+    })
+  }
+
+  #[test]
+  fn frame_tcp() {
+    async_std::task::block_on(async {
+      let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap();
+      let addr = listener.local_addr().unwrap();
+      // echoes all the queries
+      let echo = async_std::task::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut rd = frame::FrameReader::new(stream.clone());
+        let mut wr = frame::FrameWriter::new(stream);
+        while let Some(q) = rd.recv::<Sequence<ClientQuery>>().await.unwrap() {
+          wr.send(&q).await.unwrap();
+        }
+      });
+      let stream = async_std::net::TcpStream::connect(addr).await.unwrap();
+      let mut rd = frame::FrameReader::new(stream.clone());
+      let mut wr = frame::FrameWriter::new(stream.clone());
+      for q in queries() {
+        wr.send(&q).await.unwrap();
+        assert_eq!(rd.recv().await.unwrap(), Some(q));
+      }
+      stream.shutdown(std::net::Shutdown::Write).unwrap();
+      echo.await;
+    })
+  }
 }
@@ -0,0 +1,849 @@
+/* serde support for the network protocol described in the README.md file
+
+  Any type implementing `Serialize` / `Deserialize` can be sent on the wire using `to_writer` and
+  `from_reader`. The encoding is the same as the hand written one:
+
+   * u8 values are written as a single byte (this is how the arrays in `AuthMessage` are encoded),
+   * other unsigned numbers are written using the variable length encoding (see `encode::u128`),
+   * signed numbers are zigzag-encoded, then written as unsigned numbers,
+   * enums are a single tag byte, followed by the variant content,
+   * sequences, maps, strings and byte arrays are prefixed by their length,
+   * structs and tuples are the concatenation of their fields.
+
+  The format is not self describing, so `deserialize_any` is not supported.
+*/
+use std::io::{Read, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+use super::decode::{DecodeError, DecodeLimits, Limit};
+use super::encode;
+
+/// serialization errors, decoding errors are `DecodeError`
+#[derive(Debug)]
+pub enum Error {
+  Io(std::io::Error),
+  /// sequences and maps must know their length before being serialized
+  UnknownLength,
+  /// more than 256 variants in an enum
+  TagOverflow(u32),
+  Message(String),
+}
+
+pub type Result<A> = std::result::Result<A, Error>;
+type DResult<A> = std::result::Result<A, DecodeError>;
+
+impl std::fmt::Display for Error {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Error::Io(rr) => rr.fmt(f),
+      Error::UnknownLength => "UnknownLength".fmt(f),
+      Error::TagOverflow(tag) => write!(f, "TagOverflow({})", tag),
+      Error::Message(msg) => msg.fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Io(rr) => Some(rr),
+      _ => None,
+    }
+  }
+}
+
+impl ser::Error for Error {
+  fn custom<T: std::fmt::Display>(msg: T) -> Self {
+    Error::Message(msg.to_string())
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from(value: std::io::Error) -> Self {
+    Error::Io(value)
+  }
+}
+
+impl From<Error> for std::io::Error {
+  fn from(value: Error) -> Self {
+    match value {
+      Error::Io(rr) => rr,
+      rr => std::io::Error::new(std::io::ErrorKind::InvalidData, rr),
+    }
+  }
+}
+
+pub fn to_writer<W, T>(w: W, value: &T) -> Result<()>
+where
+  W: Write,
+  T: Serialize + ?Sized,
+{
+  value.serialize(&mut Serializer::new(w))
+}
+
+pub fn to_bytes<T>(value: &T) -> Result<Vec<u8>>
+where
+  T: Serialize + ?Sized,
+{
+  let mut out = Vec::new();
+  to_writer(&mut out, value)?;
+  Ok(out)
+}
+
+pub fn from_reader<R, T>(rd: R) -> DResult<T>
+where
+  R: Read,
+  T: de::DeserializeOwned,
+{
+  from_reader_with_limits(rd, DecodeLimits::default())
+}
+
+pub fn from_reader_with_limits<R, T>(rd: R, limits: DecodeLimits) -> DResult<T>
+where
+  R: Read,
+  T: de::DeserializeOwned,
+{
+  Deserializer::with_limits(rd, limits).deserialize()
+}
+
+fn zigzag(v: i128) -> u128 {
+  ((v << 1) ^ (v >> 127)) as u128
+}
+
+fn unzigzag(v: u128) -> i128 {
+  ((v >> 1) as i128) ^ -((v & 1) as i128)
+}
+
+pub struct Serializer<W> {
+  w: W,
+}
+
+impl<W: Write> Serializer<W> {
+  pub fn new(w: W) -> Self {
+    Serializer { w }
+  }
+
+  pub fn into_inner(self) -> W {
+    self.w
+  }
+
+  fn tag(&mut self, variant_index: u32) -> Result<()> {
+    let tag = u8::try_from(variant_index).map_err(|_| Error::TagOverflow(variant_index))?;
+    Ok(self.w.write_u8(tag)?)
+  }
+
+  fn length(&mut self, len: Option<usize>) -> Result<()> {
+    let len = len.ok_or(Error::UnknownLength)?;
+    Ok(encode::u128(&mut self.w, len as u128)?)
+  }
+}
+
+impl<W: Write> ser::Serializer for &mut Serializer<W> {
+  type Ok = ();
+  type Error = Error;
+  type SerializeSeq = Self;
+  type SerializeTuple = Self;
+  type SerializeTupleStruct = Self;
+  type SerializeTupleVariant = Self;
+  type SerializeMap = Self;
+  type SerializeStruct = Self;
+  type SerializeStructVariant = Self;
+
+  fn is_human_readable(&self) -> bool {
+    false
+  }
+
+  fn serialize_bool(self, v: bool) -> Result<()> {
+    Ok(self.w.write_u8(v as u8)?)
+  }
+
+  fn serialize_i8(self, v: i8) -> Result<()> {
+    Ok(self.w.write_i8(v)?)
+  }
+
+  fn serialize_i16(self, v: i16) -> Result<()> {
+    self.serialize_i128(v as i128)
+  }
+
+  fn serialize_i32(self, v: i32) -> Result<()> {
+    self.serialize_i128(v as i128)
+  }
+
+  fn serialize_i64(self, v: i64) -> Result<()> {
+    self.serialize_i128(v as i128)
+  }
+
+  fn serialize_i128(self, v: i128) -> Result<()> {
+    self.serialize_u128(zigzag(v))
+  }
+
+  fn serialize_u8(self, v: u8) -> Result<()> {
+    Ok(self.w.write_u8(v)?)
+  }
+
+  fn serialize_u16(self, v: u16) -> Result<()> {
+    self.serialize_u128(v as u128)
+  }
+
+  fn serialize_u32(self, v: u32) -> Result<()> {
+    self.serialize_u128(v as u128)
+  }
+
+  fn serialize_u64(self, v: u64) -> Result<()> {
+    self.serialize_u128(v as u128)
+  }
+
+  fn serialize_u128(self, v: u128) -> Result<()> {
+    Ok(encode::u128(&mut self.w, v)?)
+  }
+
+  fn serialize_f32(self, v: f32) -> Result<()> {
+    Ok(self.w.write_f32::<LittleEndian>(v)?)
+  }
+
+  fn serialize_f64(self, v: f64) -> Result<()> {
+    Ok(self.w.write_f64::<LittleEndian>(v)?)
+  }
+
+  fn serialize_char(self, v: char) -> Result<()> {
+    self.serialize_str(v.encode_utf8(&mut [0; 4]))
+  }
+
+  fn serialize_str(self, v: &str) -> Result<()> {
+    Ok(encode::string(&mut self.w, v)?)
+  }
+
+  fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+    self.length(Some(v.len()))?;
+    Ok(self.w.write_all(v)?)
+  }
+
+  fn serialize_none(self) -> Result<()> {
+    self.tag(0)
+  }
+
+  fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+    self.tag(1)?;
+    value.serialize(self)
+  }
+
+  fn serialize_unit(self) -> Result<()> {
+    Ok(())
+  }
+
+  fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+    Ok(())
+  }
+
+  fn serialize_unit_variant(
+    self,
+    _name: &'static str,
+    variant_index: u32,
+    _variant: &'static str,
+  ) -> Result<()> {
+    self.tag(variant_index)
+  }
+
+  fn serialize_newtype_struct<T: Serialize + ?Sized>(
+    self,
+    _name: &'static str,
+    value: &T,
+  ) -> Result<()> {
+    value.serialize(self)
+  }
+
+  fn serialize_newtype_variant<T: Serialize + ?Sized>(
+    self,
+    _name: &'static str,
+    variant_index: u32,
+    _variant: &'static str,
+    value: &T,
+  ) -> Result<()> {
+    self.tag(variant_index)?;
+    value.serialize(self)
+  }
+
+  fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+    self.length(len)?;
+    Ok(self)
+  }
+
+  fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+    Ok(self)
+  }
+
+  fn serialize_tuple_struct(
+    self,
+    _name: &'static str,
+    _len: usize,
+  ) -> Result<Self::SerializeTupleStruct> {
+    Ok(self)
+  }
+
+  fn serialize_tuple_variant(
+    self,
+    _name: &'static str,
+    variant_index: u32,
+    _variant: &'static str,
+    _len: usize,
+  ) -> Result<Self::SerializeTupleVariant> {
+    self.tag(variant_index)?;
+    Ok(self)
+  }
+
+  fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap> {
+    self.length(len)?;
+    Ok(self)
+  }
+
+  fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+    Ok(self)
+  }
+
+  fn serialize_struct_variant(
+    self,
+    _name: &'static str,
+    variant_index: u32,
+    _variant: &'static str,
+    _len: usize,
+  ) -> Result<Self::SerializeStructVariant> {
+    self.tag(variant_index)?;
+    Ok(self)
+  }
+}
+
+impl<W: Write> ser::SerializeSeq for &mut Serializer<W> {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+    value.serialize(&mut **self)
+  }
+
+  fn end(self) -> Result<()> {
+    Ok(())
+  }
+}
+
+impl<W: Write> ser::SerializeTuple for &mut Serializer<W> {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+    value.serialize(&mut **self)
+  }
+
+  fn end(self) -> Result<()> {
+    Ok(())
+  }
+}
+
+impl<W: Write> ser::SerializeTupleStruct for &mut Serializer<W> {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+    value.serialize(&mut **self)
+  }
+
+  fn end(self) -> Result<()> {
+    Ok(())
+  }
+}
+
+impl<W: Write> ser::SerializeTupleVariant for &mut Serializer<W> {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+    value.serialize(&mut **self)
+  }
+
+  fn end(self) -> Result<()> {
+    Ok(())
+  }
+}
+
+impl<W: Write> ser::SerializeMap for &mut Serializer<W> {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+    key.serialize(&mut **self)
+  }
+
+  fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+    value.serialize(&mut **self)
+  }
+
+  fn end(self) -> Result<()> {
+    Ok(())
+  }
+}
+
+impl<W: Write> ser::SerializeStruct for &mut Serializer<W> {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(
+    &mut self,
+    _key: &'static str,
+    value: &T,
+  ) -> Result<()> {
+    value.serialize(&mut **self)
+  }
+
+  fn end(self) -> Result<()> {
+    Ok(())
+  }
+}
+
+impl<W: Write> ser::SerializeStructVariant for &mut Serializer<W> {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(
+    &mut self,
+    _key: &'static str,
+    value: &T,
+  ) -> Result<()> {
+    value.serialize(&mut **self)
+  }
+
+  fn end(self) -> Result<()> {
+    Ok(())
+  }
+}
+
+pub struct Deserializer<R> {
+  rd: R,
+  limits: DecodeLimits,
+  /// number of bytes read so far
+  consumed: usize,
+  depth: usize,
+  /// reject numbers that are not encoded in the shortest possible form
+  canonical: bool,
+}
+
+impl<R: Read> Deserializer<R> {
+  pub fn new(rd: R) -> Self {
+    Self::with_limits(rd, DecodeLimits::default())
+  }
+
+  pub fn with_limits(rd: R, limits: DecodeLimits) -> Self {
+    Deserializer {
+      rd,
+      limits,
+      consumed: 0,
+      depth: 0,
+      canonical: false,
+    }
+  }
+
+  /// only accept the canonical encoding of numbers, so that a message has a single representation
+  pub fn canonical(mut self) -> Self {
+    self.canonical = true;
+    self
+  }
+
+  pub fn into_inner(self) -> R {
+    self.rd
+  }
+
+  /// number of bytes read from the underlying reader
+  pub fn consumed(&self) -> usize {
+    self.consumed
+  }
+
+  /// deserializes a value, errors from the `Deserialize` implementations get the current offset
+  pub fn deserialize<T: de::DeserializeOwned>(&mut self) -> DResult<T> {
+    T::deserialize(&mut *self).map_err(|rr| match rr {
+      DecodeError::Invalid { offset: _, message } => DecodeError::Invalid {
+        offset: self.consumed,
+        message,
+      },
+      rr => rr,
+    })
+  }
+
+  fn invalid(&self, message: String) -> DecodeError {
+    DecodeError::Invalid {
+      offset: self.consumed,
+      message,
+    }
+  }
+
+  fn io(&self, rr: std::io::Error) -> DecodeError {
+    match rr.kind() {
+      std::io::ErrorKind::UnexpectedEof => DecodeError::Truncated {
+        offset: self.consumed,
+      },
+      kind => DecodeError::Io {
+        offset: self.consumed,
+        kind,
+      },
+    }
+  }
+
+  fn limit(&self, limit: Limit) -> DecodeError {
+    DecodeError::LimitExceeded {
+      offset: self.consumed,
+      limit,
+    }
+  }
+
+  fn check_total(&self, len: usize) -> DResult<()> {
+    if len > self.limits.max_total_bytes - self.consumed.min(self.limits.max_total_bytes) {
+      return Err(self.limit(Limit::TotalBytes));
+    }
+    Ok(())
+  }
+
+  fn read_array<const N: usize>(&mut self) -> DResult<[u8; N]> {
+    self.check_total(N)?;
+    let mut buf = [0; N];
+    self.rd.read_exact(&mut buf).map_err(|rr| self.io(rr))?;
+    self.consumed += N;
+    Ok(buf)
+  }
+
+  fn read_u8(&mut self) -> DResult<u8> {
+    self.read_array::<1>().map(|[b]| b)
+  }
+
+  fn length(&mut self) -> DResult<u128> {
+    self.unsigned()
+  }
+
+  fn entries(&mut self) -> DResult<usize> {
+    let len = self.length()?;
+    if len > self.limits.max_collection_entries as u128 {
+      return Err(self.limit(Limit::CollectionEntries));
+    }
+    Ok(len as usize)
+  }
+
+  fn bytes(&mut self) -> DResult<Vec<u8>> {
+    let len = self.length()?;
+    if len > self.limits.max_string_bytes as u128 {
+      return Err(self.limit(Limit::StringBytes));
+    }
+    let len = len as usize;
+    self.check_total(len)?;
+    // do not trust the length for the allocation, only allocate what was actually read
+    let mut buf = Vec::new();
+    let r = (&mut self.rd).take(len as u64).read_to_end(&mut buf);
+    self.consumed += buf.len();
+    r.map_err(|rr| self.io(rr))?;
+    if buf.len() != len {
+      return Err(DecodeError::Truncated {
+        offset: self.consumed,
+      });
+    }
+    Ok(buf)
+  }
+
+  // see encode::u128 for the description of this encoding
+  fn unsigned(&mut self) -> DResult<u128> {
+    let offset = self.consumed;
+    let (value, minimum) = match self.read_u8()? {
+      x if x < 251 => (x as u128, 0),
+      251 => (u16::from_le_bytes(self.read_array()?) as u128, 251),
+      252 => (u32::from_le_bytes(self.read_array()?) as u128, 1 << 16),
+      253 => (u64::from_le_bytes(self.read_array()?) as u128, 1 << 32),
+      254 => (u128::from_le_bytes(self.read_array()?), 1 << 64),
+      tag => return Err(DecodeError::UnknownTag { offset, tag }),
+    };
+    if self.canonical && value < minimum {
+      return Err(DecodeError::NonCanonicalVarint { offset });
+    }
+    Ok(value)
+  }
+
+  fn string(&mut self) -> DResult<String> {
+    let bytes = self.bytes()?;
+    let start = self.consumed - bytes.len();
+    String::from_utf8(bytes).map_err(|rr| DecodeError::InvalidUtf8 {
+      offset: start + rr.utf8_error().valid_up_to(),
+    })
+  }
+
+  fn nested<A, F>(&mut self, f: F) -> DResult<A>
+  where
+    F: FnOnce(&mut Self) -> DResult<A>,
+  {
+    if self.depth >= self.limits.max_nesting {
+      return Err(self.limit(Limit::Nesting));
+    }
+    self.depth += 1;
+    let r = f(self);
+    self.depth -= 1;
+    r
+  }
+}
+
+macro_rules! unsigned {
+  ($fname:ident, $visit:ident, $ty:ty) => {
+    fn $fname<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+      let v = self.unsigned()?;
+      let v = <$ty>::try_from(v)
+        .map_err(|_| self.invalid(format!("{} does not fit in {}", v, stringify!($ty))))?;
+      visitor.$visit(v)
+    }
+  };
+}
+
+macro_rules! signed {
+  ($fname:ident, $visit:ident, $ty:ty) => {
+    fn $fname<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+      let v = unzigzag(self.unsigned()?);
+      let v = <$ty>::try_from(v)
+        .map_err(|_| self.invalid(format!("{} does not fit in {}", v, stringify!($ty))))?;
+      visitor.$visit(v)
+    }
+  };
+}
+
+impl<'de, R: Read> de::Deserializer<'de> for &mut Deserializer<R> {
+  type Error = DecodeError;
+
+  fn is_human_readable(&self) -> bool {
+    false
+  }
+
+  fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> DResult<V::Value> {
+    Err(self.invalid("deserialize_any is not supported".to_string()))
+  }
+
+  fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    match self.read_u8()? {
+      0 => visitor.visit_bool(false),
+      1 => visitor.visit_bool(true),
+      x => Err(self.invalid(format!("invalid boolean value {}", x))),
+    }
+  }
+
+  fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_i8(self.read_u8()? as i8)
+  }
+
+  signed!(deserialize_i16, visit_i16, i16);
+  signed!(deserialize_i32, visit_i32, i32);
+  signed!(deserialize_i64, visit_i64, i64);
+
+  fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_i128(unzigzag(self.unsigned()?))
+  }
+
+  fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_u8(self.read_u8()?)
+  }
+
+  unsigned!(deserialize_u16, visit_u16, u16);
+  unsigned!(deserialize_u32, visit_u32, u32);
+  unsigned!(deserialize_u64, visit_u64, u64);
+
+  fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_u128(self.unsigned()?)
+  }
+
+  fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_f32(f32::from_le_bytes(self.read_array()?))
+  }
+
+  fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_f64(f64::from_le_bytes(self.read_array()?))
+  }
+
+  fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    let s = self.string()?;
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+      (Some(c), None) => visitor.visit_char(c),
+      _ => Err(self.invalid(format!("expected a single char, got {:?}", s))),
+    }
+  }
+
+  fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_string(self.string()?)
+  }
+
+  fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_string(self.string()?)
+  }
+
+  fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_byte_buf(self.bytes()?)
+  }
+
+  fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_byte_buf(self.bytes()?)
+  }
+
+  fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    let offset = self.consumed;
+    match self.read_u8()? {
+      0 => visitor.visit_none(),
+      1 => self.nested(|de| visitor.visit_some(de)),
+      tag => Err(DecodeError::UnknownTag { offset, tag }),
+    }
+  }
+
+  fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    visitor.visit_unit()
+  }
+
+  fn deserialize_unit_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    visitor: V,
+  ) -> DResult<V::Value> {
+    visitor.visit_unit()
+  }
+
+  fn deserialize_newtype_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    visitor: V,
+  ) -> DResult<V::Value> {
+    visitor.visit_newtype_struct(self)
+  }
+
+  fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    let len = self.entries()?;
+    self.nested(|de| visitor.visit_seq(Access { de, len }))
+  }
+
+  fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> DResult<V::Value> {
+    self.nested(|de| visitor.visit_seq(Access { de, len }))
+  }
+
+  fn deserialize_tuple_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    len: usize,
+    visitor: V,
+  ) -> DResult<V::Value> {
+    self.nested(|de| visitor.visit_seq(Access { de, len }))
+  }
+
+  fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> DResult<V::Value> {
+    let len = self.entries()?;
+    self.nested(|de| visitor.visit_map(Access { de, len }))
+  }
+
+  fn deserialize_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    fields: &'static [&'static str],
+    visitor: V,
+  ) -> DResult<V::Value> {
+    let len = fields.len();
+    self.nested(|de| visitor.visit_seq(Access { de, len }))
+  }
+
+  fn deserialize_enum<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    _variants: &'static [&'static str],
+    visitor: V,
+  ) -> DResult<V::Value> {
+    self.nested(|de| visitor.visit_enum(de))
+  }
+
+  fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> DResult<V::Value> {
+    Err(self.invalid("deserialize_identifier is not supported".to_string()))
+  }
+
+  fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> DResult<V::Value> {
+    Err(self.invalid("deserialize_ignored_any is not supported".to_string()))
+  }
+}
+
+/// accessor for sequences, tuples, structs and maps, that are all a known number of elements
+struct Access<'a, R> {
+  de: &'a mut Deserializer<R>,
+  len: usize,
+}
+
+impl<'de, 'a, R: Read> de::SeqAccess<'de> for Access<'a, R> {
+  type Error = DecodeError;
+
+  fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> DResult<Option<T::Value>> {
+    if self.len == 0 {
+      return Ok(None);
+    }
+    self.len -= 1;
+    seed.deserialize(&mut *self.de).map(Some)
+  }
+
+  fn size_hint(&self) -> Option<usize> {
+    Some(self.len)
+  }
+}
+
+impl<'de, 'a, R: Read> de::MapAccess<'de> for Access<'a, R> {
+  type Error = DecodeError;
+
+  fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> DResult<Option<K::Value>> {
+    if self.len == 0 {
+      return Ok(None);
+    }
+    self.len -= 1;
+    seed.deserialize(&mut *self.de).map(Some)
+  }
+
+  fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> DResult<V::Value> {
+    seed.deserialize(&mut *self.de)
+  }
+
+  fn size_hint(&self) -> Option<usize> {
+    Some(self.len)
+  }
+}
+
+impl<'de, R: Read> de::EnumAccess<'de> for &mut Deserializer<R> {
+  type Error = DecodeError;
+  type Variant = Self;
+
+  fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> DResult<(V::Value, Self)> {
+    let offset = self.consumed;
+    let tag = self.read_u8()?;
+    let value = seed
+      .deserialize(IntoDeserializer::<DecodeError>::into_deserializer(
+        tag as u32,
+      ))
+      .map_err(|_| DecodeError::UnknownTag { offset, tag })?;
+    Ok((value, self))
+  }
+}
+
+impl<'de, R: Read> de::VariantAccess<'de> for &mut Deserializer<R> {
+  type Error = DecodeError;
+
+  fn unit_variant(self) -> DResult<()> {
+    Ok(())
+  }
+
+  fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> DResult<T::Value> {
+    seed.deserialize(self)
+  }
+
+  fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> DResult<V::Value> {
+    visitor.visit_seq(Access { de: self, len })
+  }
+
+  fn struct_variant<V: Visitor<'de>>(
+    self,
+    fields: &'static [&'static str],
+    visitor: V,
+  ) -> DResult<V::Value> {
+    visitor.visit_seq(Access {
+      de: self,
+      len: fields.len(),
+    })
+  }
+}
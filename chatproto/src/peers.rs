@@ -0,0 +1,833 @@
+/* Server to server authentication.
+
+  Each pair of servers shares a pre-shared key (PSK). Before accepting any `ServerMessage` from
+  another server, a session is established with a handshake similar to the one in the `auth`
+  module:
+
+   * initiator -> responder: `PeerMessage::Hello { server, nonce }`,
+   * responder -> initiator: `PeerMessage::Nonce { server, nonce }`,
+   * initiator -> responder: `PeerMessage::Auth { server, response }`, proving it knows the PSK,
+   * responder -> initiator: `PeerMessage::Auth { server, response }`, proving it knows the PSK.
+
+  Both sides then derive the session key from the PSK and both nonces. A `Hello` is reserved to
+  the address it came from for `HANDSHAKE_TTL`, and a wrong proof does not cancel the handshake,
+  so that spoofed messages cannot break a handshake in progress. Server messages are sent
+  as `PeerMessage::Message`, with a sequence number and a MAC of the source, sequence number and
+  encoded message. Messages from servers without a session, with an invalid MAC or that are
+  replayed are rejected, so that nobody can inject routes with `Announce`. Messages can arrive
+  out of order, within the last `REPLAY_WINDOW` sequence numbers.
+
+  Connected servers measure the round-trip time of their link with `Ping` and `Pong`, it is used
+  as the cost of the link in announces. They are authenticated like messages, so that nobody can
+  make a link look cheaper or more expensive than it is.
+*/
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::auth::{mac, new_nonce, verify, Mac, Nonce};
+use crate::core::{Clock, SystemClock};
+use crate::messages::{PeerMessage, ServerId, ServerMessage};
+use crate::netproto::serde::to_bytes;
+
+/// number of sequence numbers, below the highest received, that are still accepted once
+pub const REPLAY_WINDOW: u128 = 64;
+
+/// how long a pending handshake started by a peer is reserved to the address of its `Hello`
+pub const HANDSHAKE_TTL: Duration = Duration::from_secs(10);
+
+/// pre-shared key for a server, parsed from `<server id>=<hex key>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerKey {
+  pub server: ServerId,
+  pub key: Vec<u8>,
+}
+
+impl std::str::FromStr for PeerKey {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (server, key) = s
+      .split_once('=')
+      .ok_or_else(|| format!("expected <server id>=<hex key>, got {}", s))?;
+    let server = server.parse().map_err(|rr| format!("{}: {}", server, rr))?;
+    if key.is_empty() || key.len() % 2 != 0 {
+      return Err(format!("invalid key length for {}", server));
+    }
+    let key = (0..key.len())
+      .step_by(2)
+      .map(|i| u8::from_str_radix(&key[i..i + 2], 16))
+      .collect::<Result<Vec<u8>, _>>()
+      .map_err(|rr| format!("invalid key for {}: {}", server, rr))?;
+    Ok(PeerKey { server, key })
+  }
+}
+
+/// address of the server port of a peer, parsed from `<server id>@<address>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerAddr {
+  pub server: ServerId,
+  pub addr: SocketAddr,
+}
+
+impl std::str::FromStr for PeerAddr {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (server, addr) = s
+      .split_once('@')
+      .ok_or_else(|| format!("expected <server id>@<address>, got {}", s))?;
+    let server = server.parse().map_err(|rr| format!("{}: {}", server, rr))?;
+    let addr = addr.parse().map_err(|rr| format!("{}: {}", addr, rr))?;
+    Ok(PeerAddr { server, addr })
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerError {
+  /// no key is configured for this server
+  UnknownPeer(ServerId),
+  /// the message is not the expected step of the handshake
+  UnexpectedMessage(ServerId),
+  BadResponse(ServerId),
+  /// no session with this server
+  NotConnected(ServerId),
+  BadMac(ServerId),
+  /// the sequence number did not increase
+  Replayed(ServerId),
+  Encode(String),
+}
+
+impl std::fmt::Display for PeerError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      PeerError::UnknownPeer(server) => write!(f, "UnknownPeer({})", server),
+      PeerError::UnexpectedMessage(server) => write!(f, "UnexpectedMessage({})", server),
+      PeerError::BadResponse(server) => write!(f, "BadResponse({})", server),
+      PeerError::NotConnected(server) => write!(f, "NotConnected({})", server),
+      PeerError::BadMac(server) => write!(f, "BadMac({})", server),
+      PeerError::Replayed(server) => write!(f, "Replayed({})", server),
+      PeerError::Encode(rr) => write!(f, "Encode({})", rr),
+    }
+  }
+}
+
+impl std::error::Error for PeerError {}
+
+/// result of handling a `PeerMessage`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerEvent {
+  /// must be sent back to the peer
+  Reply(PeerMessage),
+  /// the handshake started by this server is complete, the reply must be sent back
+  Accepted(ServerId, PeerMessage),
+  /// the handshake with this server is complete
+  Connected(ServerId),
+  /// an authenticated message, that can be handled by the `MessageServer`
+  Message(ServerId, ServerMessage),
+  /// a `Pong` was received, with the round-trip time of the link
+  Measured(ServerId, Duration),
+}
+
+/// a handshake started by this server
+enum Handshake {
+  /// we sent `Hello`
+  Initiated { mine: Nonce },
+  /// we sent `Auth`, and wait for the proof of the responder
+  Confirming { mine: Nonce, theirs: Nonce },
+}
+
+/// a handshake started by a peer, we replied to its `Hello`
+struct Response {
+  mine: Nonce,
+  theirs: Nonce,
+  /// where the `Hello` came from, and when
+  peer: SocketAddr,
+  issued: Instant,
+}
+
+struct PeerSession {
+  key: Mac,
+  sent: u128,
+  /// highest sequence number received
+  received: u128,
+  /// the sequence numbers received in the window below `received`, bit n is `received - 1 - n`
+  window: u64,
+  /// the nonce of the last ping, and when it was sent
+  ping: Option<(Nonce, Instant)>,
+  /// smoothed round-trip time
+  rtt: Option<Duration>,
+}
+
+impl PeerSession {
+  fn new(key: Mac) -> Self {
+    PeerSession {
+      key,
+      sent: 0,
+      received: 0,
+      window: 0,
+      ping: None,
+      rtt: None,
+    }
+  }
+
+  /// records a received sequence number, false if it was already received or is too old
+  fn receive(&mut self, seqid: u128) -> bool {
+    if seqid > self.received {
+      // the previous highest number moves into the window
+      let shift = seqid - self.received;
+      self.window = match shift {
+        1..=63 => (self.window << shift) | (1 << (shift - 1)),
+        64 => 1 << 63,
+        _ => 0,
+      };
+      self.received = seqid;
+      return true;
+    }
+    let age = self.received - seqid;
+    if seqid == 0 || !(1..=REPLAY_WINDOW).contains(&age) {
+      return false;
+    }
+    let bit = 1 << (age - 1);
+    let fresh = self.window & bit == 0;
+    self.window |= bit;
+    fresh
+  }
+}
+
+/// Authentication state for all the peers of a server
+pub struct Peers {
+  id: ServerId,
+  clock: Arc<dyn Clock>,
+  keys: HashMap<ServerId, Vec<u8>>,
+  handshakes: HashMap<ServerId, Handshake>,
+  responses: HashMap<ServerId, Response>,
+  sessions: HashMap<ServerId, PeerSession>,
+}
+
+impl Peers {
+  pub fn new(id: ServerId, keys: impl IntoIterator<Item = PeerKey>) -> Self {
+    Self::with_clock(id, keys, Arc::new(SystemClock))
+  }
+
+  /// peers that read the time from `clock`, for the handshakes and round-trip times
+  pub fn with_clock(
+    id: ServerId,
+    keys: impl IntoIterator<Item = PeerKey>,
+    clock: Arc<dyn Clock>,
+  ) -> Self {
+    Peers {
+      id,
+      clock,
+      keys: keys.into_iter().map(|k| (k.server, k.key)).collect(),
+      handshakes: HashMap::new(),
+      responses: HashMap::new(),
+      sessions: HashMap::new(),
+    }
+  }
+
+  pub fn id(&self) -> ServerId {
+    self.id
+  }
+
+  pub fn is_connected(&self, server: &ServerId) -> bool {
+    self.sessions.contains_key(server)
+  }
+
+  /// Cost of the link with a connected server, for the `metrics` of announces: the round-trip
+  /// time in milliseconds, or 1 if it was not measured yet.
+  pub fn metric(&self, server: &ServerId) -> u32 {
+    self
+      .sessions
+      .get(server)
+      .and_then(|s| s.rtt)
+      .map(|rtt| rtt.as_millis().clamp(1, u32::MAX as u128) as u32)
+      .unwrap_or(1)
+  }
+
+  /// starts a round-trip time measurement with a connected server
+  pub fn ping(&mut self, server: ServerId) -> Result<PeerMessage, PeerError> {
+    let now = self.clock.now();
+    let session = self
+      .sessions
+      .get_mut(&server)
+      .ok_or(PeerError::NotConnected(server))?;
+    let nonce = new_nonce();
+    session.ping = Some((nonce, now));
+    Ok(PeerMessage::Ping {
+      server: self.id,
+      nonce,
+      mac: Self::ping_mac(&session.key, b"ping", &self.id, &nonce),
+    })
+  }
+
+  fn key(&self, server: ServerId) -> Result<&[u8], PeerError> {
+    self
+      .keys
+      .get(&server)
+      .map(|k| k.as_slice())
+      .ok_or(PeerError::UnknownPeer(server))
+  }
+
+  // the labels and nonce order depend on who initiated the handshake
+  fn handshake_mac(
+    &self,
+    label: &[u8],
+    initiator: &ServerId,
+    responder: &ServerId,
+    ninit: &Nonce,
+    nresp: &Nonce,
+  ) -> Result<Mac, PeerError> {
+    let peer = if initiator == &self.id {
+      responder
+    } else {
+      initiator
+    };
+    Ok(mac(
+      self.key(*peer)?,
+      &[
+        label,
+        initiator.0.as_bytes(),
+        responder.0.as_bytes(),
+        ninit,
+        nresp,
+      ],
+    ))
+  }
+
+  fn message_mac(
+    key: &Mac,
+    src: &ServerId,
+    seqid: u128,
+    message: &ServerMessage,
+  ) -> Result<Mac, PeerError> {
+    let encoded = to_bytes(message).map_err(|rr| PeerError::Encode(rr.to_string()))?;
+    Ok(mac(
+      key,
+      &[b"message", src.0.as_bytes(), &seqid.to_le_bytes(), &encoded],
+    ))
+  }
+
+  fn ping_mac(key: &Mac, label: &[u8], src: &ServerId, nonce: &Nonce) -> Mac {
+    mac(key, &[label, src.0.as_bytes(), nonce])
+  }
+
+  /// starts a handshake with `server`, the message must be sent to it
+  pub fn connect(&mut self, server: ServerId) -> Result<PeerMessage, PeerError> {
+    self.key(server)?;
+    let mine = new_nonce();
+    self
+      .handshakes
+      .insert(server, Handshake::Initiated { mine });
+    Ok(PeerMessage::Hello {
+      server: self.id,
+      nonce: mine,
+    })
+  }
+
+  /// authenticates a message for `dest`, that must be connected
+  pub fn seal(&mut self, dest: ServerId, message: ServerMessage) -> Result<PeerMessage, PeerError> {
+    let session = self
+      .sessions
+      .get_mut(&dest)
+      .ok_or(PeerError::NotConnected(dest))?;
+    session.sent += 1;
+    let mac = Self::message_mac(&session.key, &self.id, session.sent, &message)?;
+    Ok(PeerMessage::Message {
+      src: self.id,
+      seqid: session.sent,
+      message,
+      mac,
+    })
+  }
+
+  /// handles a message received from `peer`
+  pub fn handle(&mut self, peer: SocketAddr, msg: PeerMessage) -> Result<PeerEvent, PeerError> {
+    match msg {
+      PeerMessage::Hello { server, nonce } => {
+        self.key(server)?;
+        // both sides started a handshake, the one with the smallest id is the initiator
+        if let Some(Handshake::Initiated { .. }) = self.handshakes.get(&server) {
+          if self.id < server {
+            return Err(PeerError::UnexpectedMessage(server));
+          }
+        }
+        // the pending response can only be replaced from the same address, or once it expired
+        let now = self.clock.now();
+        if let Some(r) = self.responses.get(&server) {
+          if r.peer != peer && now.duration_since(r.issued) < HANDSHAKE_TTL {
+            return Err(PeerError::UnexpectedMessage(server));
+          }
+        }
+        let mine = new_nonce();
+        self.responses.insert(
+          server,
+          Response {
+            mine,
+            theirs: nonce,
+            peer,
+            issued: now,
+          },
+        );
+        Ok(PeerEvent::Reply(PeerMessage::Nonce {
+          server: self.id,
+          nonce: mine,
+        }))
+      }
+      PeerMessage::Nonce { server, nonce } => {
+        let mine = match self.handshakes.get(&server) {
+          Some(Handshake::Initiated { mine }) => *mine,
+          _ => return Err(PeerError::UnexpectedMessage(server)),
+        };
+        let response = self.handshake_mac(b"initiator", &self.id, &server, &mine, &nonce)?;
+        self.handshakes.insert(
+          server,
+          Handshake::Confirming {
+            mine,
+            theirs: nonce,
+          },
+        );
+        Ok(PeerEvent::Reply(PeerMessage::Auth {
+          server: self.id,
+          response,
+        }))
+      }
+      PeerMessage::Auth { server, response } => {
+        // the pending handshakes are kept until a valid proof arrives
+        let mut pending = false;
+        if let Some(&Response { mine, theirs, .. }) = self.responses.get(&server) {
+          pending = true;
+          let expected = self.handshake_mac(b"initiator", &server, &self.id, &theirs, &mine)?;
+          if verify(&expected, &response) {
+            self.responses.remove(&server);
+            self.handshakes.remove(&server);
+            let key = self.handshake_mac(b"session", &server, &self.id, &theirs, &mine)?;
+            self.sessions.insert(server, PeerSession::new(key));
+            let response = self.handshake_mac(b"responder", &server, &self.id, &theirs, &mine)?;
+            return Ok(PeerEvent::Accepted(
+              server,
+              PeerMessage::Auth {
+                server: self.id,
+                response,
+              },
+            ));
+          }
+        }
+        if let Some(&Handshake::Confirming { mine, theirs }) = self.handshakes.get(&server) {
+          pending = true;
+          let expected = self.handshake_mac(b"responder", &self.id, &server, &mine, &theirs)?;
+          if verify(&expected, &response) {
+            self.responses.remove(&server);
+            self.handshakes.remove(&server);
+            let key = self.handshake_mac(b"session", &self.id, &server, &mine, &theirs)?;
+            self.sessions.insert(server, PeerSession::new(key));
+            return Ok(PeerEvent::Connected(server));
+          }
+        }
+        if pending {
+          Err(PeerError::BadResponse(server))
+        } else {
+          Err(PeerError::UnexpectedMessage(server))
+        }
+      }
+      PeerMessage::Message {
+        src,
+        seqid,
+        message,
+        mac,
+      } => {
+        let session = self
+          .sessions
+          .get_mut(&src)
+          .ok_or(PeerError::NotConnected(src))?;
+        let expected = Self::message_mac(&session.key, &src, seqid, &message)?;
+        if !verify(&expected, &mac) {
+          return Err(PeerError::BadMac(src));
+        }
+        if !session.receive(seqid) {
+          return Err(PeerError::Replayed(src));
+        }
+        Ok(PeerEvent::Message(src, message))
+      }
+      PeerMessage::Ping { server, nonce, mac } => {
+        let session = self
+          .sessions
+          .get(&server)
+          .ok_or(PeerError::NotConnected(server))?;
+        if !verify(
+          &Self::ping_mac(&session.key, b"ping", &server, &nonce),
+          &mac,
+        ) {
+          return Err(PeerError::BadMac(server));
+        }
+        Ok(PeerEvent::Reply(PeerMessage::Pong {
+          server: self.id,
+          nonce,
+          mac: Self::ping_mac(&session.key, b"pong", &self.id, &nonce),
+        }))
+      }
+      PeerMessage::Pong { server, nonce, mac } => {
+        let session = self
+          .sessions
+          .get_mut(&server)
+          .ok_or(PeerError::NotConnected(server))?;
+        if !verify(
+          &Self::ping_mac(&session.key, b"pong", &server, &nonce),
+          &mac,
+        ) {
+          return Err(PeerError::BadMac(server));
+        }
+        let sent = match session.ping {
+          Some((expected, sent)) if expected == nonce => sent,
+          _ => return Err(PeerError::UnexpectedMessage(server)),
+        };
+        session.ping = None;
+        let measured = self.clock.now().duration_since(sent);
+        // smoothed like TCP round-trip times
+        let rtt = match session.rtt {
+          Some(rtt) => (rtt * 7 + measured) / 8,
+          None => measured,
+        };
+        session.rtt = Some(rtt);
+        Ok(PeerEvent::Measured(server, rtt))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::TestClock;
+
+  fn addr() -> SocketAddr {
+    "127.0.0.1:4667".parse().unwrap()
+  }
+
+  fn reply(ev: Result<PeerEvent, PeerError>) -> PeerMessage {
+    match ev {
+      Ok(PeerEvent::Reply(m)) | Ok(PeerEvent::Accepted(_, m)) => m,
+      other => panic!("expected a reply, got {:?}", other),
+    }
+  }
+
+  fn pair(key: &[u8]) -> (Peers, Peers) {
+    pair_with_clock(key, &TestClock::new())
+  }
+
+  fn pair_with_clock(key: &[u8], clock: &TestClock) -> (Peers, Peers) {
+    let a = ServerId::default();
+    let b = ServerId::default();
+    let pa = Peers::with_clock(
+      a,
+      [PeerKey {
+        server: b,
+        key: key.to_vec(),
+      }],
+      Arc::new(clock.clone()),
+    );
+    let pb = Peers::with_clock(
+      b,
+      [PeerKey {
+        server: a,
+        key: key.to_vec(),
+      }],
+      Arc::new(clock.clone()),
+    );
+    (pa, pb)
+  }
+
+  #[test]
+  fn peer_key() {
+    let k: PeerKey = "67e55044-10b1-426f-9247-bb680e5fe0c8=00ff10"
+      .parse()
+      .unwrap();
+    assert_eq!(k.key, vec![0, 255, 16]);
+    assert_eq!(
+      k.server,
+      "67e55044-10b1-426f-9247-bb680e5fe0c8".parse().unwrap()
+    );
+    assert!("67e55044-10b1-426f-9247-bb680e5fe0c8=0"
+      .parse::<PeerKey>()
+      .is_err());
+    assert!("67e55044-10b1-426f-9247-bb680e5fe0c8=zz"
+      .parse::<PeerKey>()
+      .is_err());
+    assert!("67e55044=00".parse::<PeerKey>().is_err());
+  }
+
+  #[test]
+  fn peer_addr() {
+    let p: PeerAddr = "67e55044-10b1-426f-9247-bb680e5fe0c8@127.0.0.1:4667"
+      .parse()
+      .unwrap();
+    assert_eq!(p.addr, "127.0.0.1:4667".parse().unwrap());
+    assert!("67e55044-10b1-426f-9247-bb680e5fe0c8@localhost"
+      .parse::<PeerAddr>()
+      .is_err());
+    assert!("127.0.0.1:4667".parse::<PeerAddr>().is_err());
+  }
+
+  #[test]
+  fn peer_handshake() {
+    let (mut a, mut b) = pair(b"secret");
+    let hello = a.connect(b.id()).unwrap();
+    let nonce = reply(b.handle(addr(), hello));
+    let auth = reply(a.handle(addr(), nonce));
+    let confirm = reply(b.handle(addr(), auth));
+    assert_eq!(a.handle(addr(), confirm), Ok(PeerEvent::Connected(b.id())));
+    assert!(a.is_connected(&b.id()) && b.is_connected(&a.id()));
+
+    // both directions work
+    let m = ServerMessage::Announce {
+      route: vec![a.id()],
+      clients: HashMap::new(),
+    };
+    let sealed = a.seal(b.id(), m.clone()).unwrap();
+    assert_eq!(
+      b.handle(addr(), sealed.clone()),
+      Ok(PeerEvent::Message(a.id(), m.clone()))
+    );
+    assert_eq!(b.handle(addr(), sealed), Err(PeerError::Replayed(a.id())));
+    let sealed = b.seal(a.id(), m.clone()).unwrap();
+    assert_eq!(a.handle(addr(), sealed), Ok(PeerEvent::Message(b.id(), m)));
+  }
+
+  #[test]
+  fn peer_simultaneous_handshake() {
+    let (mut a, mut b) = pair(b"secret");
+    let hello_a = a.connect(b.id()).unwrap();
+    let hello_b = b.connect(a.id()).unwrap();
+    let (mut low, mut high, hello_low, hello_high) = if a.id() < b.id() {
+      (a, b, hello_a, hello_b)
+    } else {
+      (b, a, hello_b, hello_a)
+    };
+    // the server with the highest id gives up its own handshake
+    assert!(low.handle(addr(), hello_high).is_err());
+    let nonce = reply(high.handle(addr(), hello_low));
+    let auth = reply(low.handle(addr(), nonce));
+    let confirm = reply(high.handle(addr(), auth));
+    assert_eq!(
+      low.handle(addr(), confirm),
+      Ok(PeerEvent::Connected(high.id()))
+    );
+  }
+
+  #[test]
+  fn peer_spoofed_hello() {
+    let clock = TestClock::new();
+    let (mut a, mut b) = pair_with_clock(b"secret", &clock);
+    let attacker: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+    let spoofed = PeerMessage::Hello {
+      server: a.id(),
+      nonce: new_nonce(),
+    };
+    let hello = a.connect(b.id()).unwrap();
+    let nonce = reply(b.handle(addr(), hello));
+    // the handshake in progress is not replaced
+    assert_eq!(
+      b.handle(attacker, spoofed.clone()),
+      Err(PeerError::UnexpectedMessage(a.id()))
+    );
+    let auth = reply(a.handle(addr(), nonce));
+    // nor cancelled by a wrong proof
+    let forged = PeerMessage::Auth {
+      server: a.id(),
+      response: [0; 16],
+    };
+    assert_eq!(
+      b.handle(attacker, forged),
+      Err(PeerError::BadResponse(a.id()))
+    );
+    let confirm = reply(b.handle(addr(), auth));
+    assert_eq!(a.handle(addr(), confirm), Ok(PeerEvent::Connected(b.id())));
+
+    // an abandoned handshake can be replaced once it expired
+    let hello = a.connect(b.id()).unwrap();
+    b.handle(addr(), hello).unwrap();
+    clock.advance(HANDSHAKE_TTL / 2);
+    assert!(b.handle(attacker, spoofed.clone()).is_err());
+    clock.advance(HANDSHAKE_TTL / 2);
+    assert!(b.handle(attacker, spoofed).is_ok());
+  }
+
+  #[test]
+  fn peer_wrong_key() {
+    let (mut a, b) = pair(b"secret");
+    let mut b = Peers::new(
+      b.id(),
+      [PeerKey {
+        server: a.id(),
+        key: b"wrong".to_vec(),
+      }],
+    );
+    let hello = a.connect(b.id()).unwrap();
+    let nonce = reply(b.handle(addr(), hello));
+    let auth = reply(a.handle(addr(), nonce));
+    assert_eq!(b.handle(addr(), auth), Err(PeerError::BadResponse(a.id())));
+    assert!(!b.is_connected(&a.id()));
+
+    // servers without a key can't even start
+    let mut c = Peers::new(b.id(), []);
+    let hello = a.connect(c.id()).unwrap();
+    assert_eq!(c.handle(addr(), hello), Err(PeerError::UnknownPeer(a.id())));
+  }
+
+  #[test]
+  fn peer_ping() {
+    let clock = TestClock::new();
+    let (mut a, mut b) = pair_with_clock(b"secret", &clock);
+    assert_eq!(a.ping(b.id()), Err(PeerError::NotConnected(b.id())));
+    let hello = a.connect(b.id()).unwrap();
+    let nonce = reply(b.handle(addr(), hello));
+    let auth = reply(a.handle(addr(), nonce));
+    let confirm = reply(b.handle(addr(), auth));
+    a.handle(addr(), confirm).unwrap();
+    assert_eq!(a.metric(&b.id()), 1);
+
+    let ping = a.ping(b.id()).unwrap();
+    clock.advance(Duration::from_millis(200));
+    let pong = reply(b.handle(addr(), ping.clone()));
+    // both are authenticated with the session key
+    let mut forged = ping;
+    if let PeerMessage::Ping { mac, .. } = &mut forged {
+      mac[0] ^= 1;
+    }
+    assert_eq!(b.handle(addr(), forged), Err(PeerError::BadMac(a.id())));
+    let mut forged = pong.clone();
+    if let PeerMessage::Pong { mac, .. } = &mut forged {
+      mac[0] ^= 1;
+    }
+    assert_eq!(a.handle(addr(), forged), Err(PeerError::BadMac(b.id())));
+    match a.handle(addr(), pong.clone()) {
+      Ok(PeerEvent::Measured(server, rtt)) => {
+        assert_eq!(server, b.id());
+        assert_eq!(rtt, Duration::from_millis(200));
+      }
+      other => panic!("expected a measure, got {:?}", other),
+    }
+    assert_eq!(a.metric(&b.id()), 200);
+    // a pong is only accepted once
+    assert_eq!(
+      a.handle(addr(), pong),
+      Err(PeerError::UnexpectedMessage(b.id()))
+    );
+  }
+
+  #[test]
+  fn peer_reordered_messages() {
+    let (mut a, mut b) = pair(b"secret");
+    connect(&mut a, &mut b).unwrap();
+    let m = ServerMessage::Withdraw {
+      route: vec![a.id()],
+      clients: Vec::new(),
+    };
+    let sealed: Vec<_> = (0..100)
+      .map(|_| a.seal(b.id(), m.clone()).unwrap())
+      .collect();
+    assert!(b.handle(addr(), sealed[10].clone()).is_ok());
+    assert!(b.handle(addr(), sealed[3].clone()).is_ok());
+    assert_eq!(
+      b.handle(addr(), sealed[3].clone()),
+      Err(PeerError::Replayed(a.id()))
+    );
+    assert_eq!(
+      b.handle(addr(), sealed[10].clone()),
+      Err(PeerError::Replayed(a.id()))
+    );
+    assert!(b.handle(addr(), sealed[80].clone()).is_ok());
+    // too old to tell if it was received
+    assert_eq!(
+      b.handle(addr(), sealed[5].clone()),
+      Err(PeerError::Replayed(a.id()))
+    );
+    assert!(b.handle(addr(), sealed[16].clone()).is_ok());
+    assert!(b.handle(addr(), sealed[79].clone()).is_ok());
+  }
+
+  // runs a complete handshake between two servers
+  fn connect(initiator: &mut Peers, responder: &mut Peers) -> Result<(), PeerError> {
+    let hello = initiator.connect(responder.id())?;
+    let nonce = reply(Ok(responder.handle(addr(), hello)?));
+    let auth = reply(Ok(initiator.handle(addr(), nonce)?));
+    let confirm = reply(Ok(responder.handle(addr(), auth)?));
+    match initiator.handle(addr(), confirm)? {
+      PeerEvent::Connected(_) => Ok(()),
+      other => panic!("expected the end of the handshake, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn peer_spoofed_announce() {
+    use crate::core::{DefaultChecker, MessageServer};
+    use crate::messages::{ClientId, ServerReply};
+    use crate::solutions::reference::Server;
+
+    let (mut local, mut remote) = pair(b"s1 pre-shared key");
+    let (sid, s1) = (local.id(), remote.id());
+    let server: Server<DefaultChecker> = MessageServer::new(DefaultChecker::default(), sid);
+    // what the server binary does with messages received on the server port
+    let deliver = |local: &mut Peers, msg| match local.handle(addr(), msg)? {
+      PeerEvent::Message(_, m) => Ok(Some(async_std::task::block_on(
+        server.handle_server_message(m),
+      ))),
+      _ => Ok(None),
+    };
+    let announce = ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(ClientId::default(), "external user".into())]),
+    };
+
+    // no session with s1
+    let forged = PeerMessage::Message {
+      src: s1,
+      seqid: 1,
+      message: announce.clone(),
+      mac: [0; 16],
+    };
+    assert_eq!(
+      deliver(&mut local, forged),
+      Err(PeerError::NotConnected(s1))
+    );
+
+    // an attacker using the id of s1, without its key
+    let mut attacker = Peers::new(
+      s1,
+      [PeerKey {
+        server: sid,
+        key: b"guessed key".to_vec(),
+      }],
+    );
+    assert_eq!(
+      connect(&mut attacker, &mut local),
+      Err(PeerError::BadResponse(s1))
+    );
+
+    connect(&mut remote, &mut local).unwrap();
+
+    // tampered announce
+    let mut tampered = remote.seal(sid, announce.clone()).unwrap();
+    if let PeerMessage::Message { message, .. } = &mut tampered {
+      *message = ServerMessage::Announce {
+        route: vec![s1],
+        clients: HashMap::from([(ClientId::default(), "hijacked user".into())]),
+      };
+    }
+    assert_eq!(deliver(&mut local, tampered), Err(PeerError::BadMac(s1)));
+    assert_eq!(async_std::task::block_on(server.route_to(s1)), None);
+
+    // the genuine announce is accepted, but only once
+    let sealed = remote.seal(sid, announce).unwrap();
+    assert_eq!(
+      deliver(&mut local, sealed.clone()),
+      Ok(Some(ServerReply::Outgoing(Vec::new())))
+    );
+    assert_eq!(deliver(&mut local, sealed), Err(PeerError::Replayed(s1)));
+    assert_eq!(
+      async_std::task::block_on(server.route_to(s1)),
+      Some(vec![sid, s1])
+    );
+  }
+}
@@ -0,0 +1,616 @@
+/* Routing between servers.
+
+  Announces carry the route they followed: the first server is the one that sent it, and the
+  last one is our neighbour. Each pair of consecutive servers in a route is linked, so the
+  announces received so far describe a graph of the federation, where the cost of each link is
+  given by the `metrics` of `WeightedAnnounce`. `Topology` keeps this graph up to date as announces
+  arrive and links disappear, and computes the cheapest routes in it, so that
+  `MessageServer::route_to` can be implemented with:
+
+    topology.route(self_id, destination)
+
+  `Topology::dot` renders this view of the federation as a Graphviz document.
+
+  Servers forward the announces, withdrawals and lost links they receive to their other
+  neighbours, but only for their best route to each server, see `Propagation`. Each announce round then costs a few
+  messages per link, instead of one per path of the federation.
+*/
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::messages::{ClientId, ServerId, ServerMessage};
+
+/// cost of the links announced without metrics
+pub const DEFAULT_COST: u32 = 1;
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+  cost: u32,
+  /// the last time the link was announced
+  seen: Instant,
+}
+
+/// graph of the servers, learned from announce routes
+#[derive(Default, Debug, Clone)]
+pub struct Topology {
+  /// neighbours of each server
+  links: BTreeMap<ServerId, BTreeMap<ServerId, Link>>,
+}
+
+impl Topology {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// adds the links of an announce route received by `local`, with their metrics
+  pub fn announce(&mut self, route: &[ServerId], metrics: &[u32], local: ServerId, now: Instant) {
+    let next = route.iter().skip(1).chain(std::iter::once(&local));
+    for (i, (a, b)) in route.iter().zip(next).enumerate() {
+      let cost = metrics.get(i).copied().unwrap_or(DEFAULT_COST);
+      self.link(*a, *b, cost, now);
+    }
+  }
+
+  /// adds a link, or updates it if it is already known
+  pub fn link(&mut self, a: ServerId, b: ServerId, cost: u32, now: Instant) {
+    if a == b {
+      return;
+    }
+    // a free link would make routes of any length equivalent
+    let link = Link {
+      cost: cost.max(1),
+      seen: now,
+    };
+    self.links.entry(a).or_default().insert(b, link);
+    self.links.entry(b).or_default().insert(a, link);
+  }
+
+  /// removes a link, returns false if it was not known
+  pub fn unlink(&mut self, a: ServerId, b: ServerId) -> bool {
+    let known = self.remove_half(a, b);
+    self.remove_half(b, a);
+    known
+  }
+
+  fn remove_half(&mut self, a: ServerId, b: ServerId) -> bool {
+    let Some(neighbours) = self.links.get_mut(&a) else {
+      return false;
+    };
+    let known = neighbours.remove(&b).is_some();
+    if neighbours.is_empty() {
+      self.links.remove(&a);
+    }
+    known
+  }
+
+  /// removes a server and all its links
+  pub fn remove_server(&mut self, server: ServerId) {
+    for neighbour in self.neighbours(server).collect::<Vec<_>>() {
+      self.unlink(server, neighbour);
+    }
+  }
+
+  /// removes the links that were not announced during the last `ttl`
+  pub fn expire(&mut self, now: Instant, ttl: Duration) {
+    for neighbours in self.links.values_mut() {
+      neighbours.retain(|_, link| now.duration_since(link.seen) <= ttl);
+    }
+    self.links.retain(|_, neighbours| !neighbours.is_empty());
+  }
+
+  /// the servers with at least one link
+  pub fn servers(&self) -> impl Iterator<Item = ServerId> + '_ {
+    self.links.keys().copied()
+  }
+
+  /// the servers linked to `server`, by increasing id
+  pub fn neighbours(&self, server: ServerId) -> impl Iterator<Item = ServerId> + '_ {
+    self
+      .links
+      .get(&server)
+      .into_iter()
+      .flat_map(|n| n.keys().copied())
+  }
+
+  pub fn is_linked(&self, a: ServerId, b: ServerId) -> bool {
+    self.cost(a, b).is_some()
+  }
+
+  /// cost of the link between two servers
+  pub fn cost(&self, a: ServerId, b: ServerId) -> Option<u32> {
+    self.links.get(&a)?.get(&b).map(|link| link.cost)
+  }
+
+  /// The cheapest route from `from` to `to`, both included.
+  /// Among the cheapest routes, the one with the smallest ids, compared from `from`, is chosen.
+  pub fn route(&self, from: ServerId, to: ServerId) -> Option<Vec<ServerId>> {
+    self
+      .search(from, Some(to))
+      .remove(&to)
+      .map(|(_, route)| route)
+  }
+
+  /// the cost of the cheapest route from `from` to `to`
+  pub fn route_cost(&self, from: ServerId, to: ServerId) -> Option<u64> {
+    self.search(from, Some(to)).get(&to).map(|(cost, _)| *cost)
+  }
+
+  /// the first hop of the cheapest route to every reachable server
+  pub fn nexthops(&self, from: ServerId) -> HashMap<ServerId, ServerId> {
+    self
+      .search(from, None)
+      .into_iter()
+      .filter_map(|(server, (_, route))| Some((server, *route.get(1)?)))
+      .collect()
+  }
+
+  /// Graphviz document of the topology seen from `local`, with the users of each server.
+  /// Servers are labelled with the next hop of their route, and the links used by the routes
+  /// from `local` are drawn in bold.
+  pub fn dot<'a>(
+    &self,
+    local: ServerId,
+    users: impl IntoIterator<Item = (ClientId, &'a str, ServerId)>,
+  ) -> String {
+    let routes = self.search(local, None);
+    let mut used = BTreeSet::new();
+    for (_, route) in routes.values() {
+      for pair in route.windows(2) {
+        used.insert((pair[0].min(pair[1]), pair[0].max(pair[1])));
+      }
+    }
+    let mut users: Vec<_> = users.into_iter().collect();
+    users.sort();
+    let mut servers: BTreeSet<ServerId> = self.servers().collect();
+    servers.insert(local);
+    servers.extend(users.iter().map(|(_, _, server)| *server));
+
+    // writing to a String can't fail
+    let mut out = String::from("graph federation {\n");
+    for server in &servers {
+      let (via, attrs) = match routes.get(server).and_then(|(_, route)| route.get(1)) {
+        _ if *server == local => (String::new(), ", shape=doublecircle"),
+        Some(nexthop) => (format!("\\nvia {}", short(nexthop)), ""),
+        None => ("\\nunreachable".to_string(), ", style=dashed"),
+      };
+      let _ = writeln!(
+        out,
+        "  \"{}\" [label=\"{}{}\"{}];",
+        server.0,
+        short(server),
+        via,
+        attrs
+      );
+    }
+    for (a, neighbours) in &self.links {
+      for (b, link) in neighbours.range(a..) {
+        let style = if used.contains(&(*a, *b)) {
+          ", style=bold"
+        } else {
+          ""
+        };
+        let _ = writeln!(
+          out,
+          "  \"{}\" -- \"{}\" [label=\"{}\"{}];",
+          a.0, b.0, link.cost, style
+        );
+      }
+    }
+    for (client, name, server) in users {
+      let _ = writeln!(
+        out,
+        "  \"{}\" [label=\"{}\", shape=box];",
+        client.0,
+        escape(name)
+      );
+      let _ = writeln!(
+        out,
+        "  \"{}\" -- \"{}\" [style=dotted];",
+        client.0, server.0
+      );
+    }
+    out.push_str("}\n");
+    out
+  }
+
+  /// Dijkstra's algorithm, returns the cheapest route to each server that was reached.
+  /// Routes of the same cost are ordered by their ids, which makes the result deterministic.
+  fn search(
+    &self,
+    from: ServerId,
+    to: Option<ServerId>,
+  ) -> HashMap<ServerId, (u64, Vec<ServerId>)> {
+    let mut done = HashMap::new();
+    let mut queue = BinaryHeap::from([Reverse((0u64, vec![from]))]);
+    while let Some(Reverse((cost, route))) = queue.pop() {
+      let cur = route[route.len() - 1];
+      if done.contains_key(&cur) {
+        continue;
+      }
+      for (next, link) in self.links.get(&cur).into_iter().flatten() {
+        if !done.contains_key(next) {
+          let mut next_route = route.clone();
+          next_route.push(*next);
+          queue.push(Reverse((cost + link.cost as u64, next_route)));
+        }
+      }
+      done.insert(cur, (cost, route));
+      if Some(cur) == to {
+        break;
+      }
+    }
+    done
+  }
+}
+
+/// Decides which announces and withdrawals a server forwards to its other neighbours, like a
+/// distance-vector protocol: only the best route to each origin is forwarded, so that announces
+/// do not travel every path of the federation.
+pub struct Propagation {
+  id: ServerId,
+  ttl: Duration,
+  /// the best route to each origin that was forwarded, ending with us
+  best: HashMap<ServerId, BestRoute>,
+}
+
+struct BestRoute {
+  route: Vec<ServerId>,
+  cost: u64,
+  /// the last time it was received
+  seen: Instant,
+}
+
+impl Propagation {
+  /// `ttl` is how long a route is kept when it is not announced again, as in the servers
+  pub fn new(id: ServerId, ttl: Duration) -> Self {
+    Propagation {
+      id,
+      ttl,
+      best: HashMap::new(),
+    }
+  }
+
+  /// Forwards a message received from `src` to the other `neighbours`, given with the cost of
+  /// the link to them. Our id is appended to the route, and the cost of the link to the metrics
+  /// of weighted announces.
+  ///
+  /// An announce is forwarded when it is the first route to its origin, when it is cheaper than
+  /// the best one, or when it refreshes the best one. A withdrawal is forwarded when it follows
+  /// the best route, and a lost link when some best routes went through it. Routes that already went through us are dropped, so that they do not loop,
+  /// and other messages are not forwarded.
+  pub fn propagate(
+    &mut self,
+    src: ServerId,
+    mut message: ServerMessage,
+    neighbours: impl IntoIterator<Item = (ServerId, u32)>,
+    now: Instant,
+  ) -> Vec<(ServerId, ServerMessage)> {
+    let id = self.id;
+    let forward = match &mut message {
+      ServerMessage::WeightedAnnounce { route, metrics, .. } => {
+        metrics.resize(route.len(), DEFAULT_COST);
+        let cost = metrics.iter().map(|c| (*c).max(1) as u64).sum();
+        self.announced(route, cost, now)
+      }
+      ServerMessage::Announce { route, .. } => {
+        let cost = route.len() as u64 * DEFAULT_COST as u64;
+        self.announced(route, cost, now)
+      }
+      ServerMessage::Withdraw { route, clients } => self.withdrawn(route, clients.is_empty()),
+      ServerMessage::Unlink { a, b } => self.unlinked(*a, *b),
+      ServerMessage::Message(_) | ServerMessage::Failure { .. } | ServerMessage::Relay { .. } => {
+        false
+      }
+    };
+    if !forward {
+      return Vec::new();
+    }
+    match &mut message {
+      ServerMessage::WeightedAnnounce { route, .. }
+      | ServerMessage::Announce { route, .. }
+      | ServerMessage::Withdraw { route, .. } => route.push(id),
+      _ => (),
+    }
+    neighbours
+      .into_iter()
+      .filter(|(dest, _)| *dest != src)
+      .map(|(dest, cost)| {
+        let mut message = message.clone();
+        if let ServerMessage::WeightedAnnounce { metrics, .. } = &mut message {
+          metrics.push(cost);
+        }
+        (dest, message)
+      })
+      .collect()
+  }
+
+  /// records an announce route, and returns true if it must be forwarded
+  fn announced(&mut self, route: &[ServerId], cost: u64, now: Instant) -> bool {
+    let Some(origin) = route.first().copied() else {
+      return false;
+    };
+    if route.contains(&self.id) {
+      return false;
+    }
+    let route: Vec<_> = route.iter().copied().chain([self.id]).collect();
+    let better = match self.best.get(&origin) {
+      None => true,
+      Some(best) => {
+        best.route == route || cost < best.cost || now.duration_since(best.seen) > self.ttl
+      }
+    };
+    if better {
+      self.best.insert(
+        origin,
+        BestRoute {
+          route,
+          cost,
+          seen: now,
+        },
+      );
+    }
+    better
+  }
+
+  /// forgets the best route if the withdrawal followed it, and returns true if it must be forwarded
+  fn withdrawn(&mut self, route: &[ServerId], whole_server: bool) -> bool {
+    let Some(origin) = route.first().copied() else {
+      return false;
+    };
+    let follows = self
+      .best
+      .get(&origin)
+      .is_some_and(|best| best.route.len() == route.len() + 1 && best.route.starts_with(route));
+    if follows && whole_server {
+      self.best.remove(&origin);
+    }
+    follows
+  }
+
+  /// forgets the best routes through the link, and returns true if there were some
+  fn unlinked(&mut self, a: ServerId, b: ServerId) -> bool {
+    let before = self.best.len();
+    self.best.retain(|_, best| {
+      !best
+        .route
+        .windows(2)
+        .any(|pair| pair == [a, b] || pair == [b, a])
+    });
+    self.best.len() != before
+  }
+}
+
+/// the first characters of the id of a server, enough to tell them apart in a drawing
+fn short(server: &ServerId) -> String {
+  server.0.simple().to_string()[..8].to_string()
+}
+
+/// escapes a string for a quoted DOT identifier
+fn escape(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn ids<const N: usize>() -> [ServerId; N] {
+    std::array::from_fn(|i| ServerId::from(i as u128))
+  }
+
+  #[test]
+  fn announces() {
+    // us - s1 - s2
+    //  |         |
+    // s5 - s4 - s3
+    let [us, s1, s2, s3, s4, s5] = ids();
+    let now = Instant::now();
+    let mut topology = Topology::new();
+    topology.announce(&[s4, s3, s2, s1], &[], us, now);
+    assert_eq!(topology.route(us, s4), Some(vec![us, s1, s2, s3, s4]));
+    topology.announce(&[s2, s3, s4, s5], &[], us, now);
+    assert_eq!(topology.route(us, s4), Some(vec![us, s5, s4]));
+    assert_eq!(topology.route(us, us), Some(vec![us]));
+    assert_eq!(topology.route(us, ServerId::from(9)), None);
+  }
+
+  #[test]
+  fn tie_breaking() {
+    // us - s1 - s3
+    //  |         |
+    // s2 -------
+    let [us, s1, s2, s3] = ids();
+    let now = Instant::now();
+    let mut topology = Topology::new();
+    topology.announce(&[s3, s2], &[], us, now);
+    topology.announce(&[s3, s1], &[], us, now);
+    assert_eq!(topology.route(us, s3), Some(vec![us, s1, s3]));
+    assert_eq!(topology.route(s3, us), Some(vec![s3, s1, us]));
+    let nexthops = topology.nexthops(us);
+    assert_eq!(nexthops, HashMap::from([(s1, s1), (s2, s2), (s3, s1)]));
+  }
+
+  #[test]
+  fn removal() {
+    let [us, s1, s2, s3] = ids();
+    let now = Instant::now();
+    let mut topology = Topology::new();
+    topology.announce(&[s3, s2], &[], us, now);
+    topology.announce(&[s3, s1], &[], us, now);
+    assert!(topology.unlink(s1, s3));
+    assert!(!topology.unlink(s1, s3));
+    assert_eq!(topology.route(us, s3), Some(vec![us, s2, s3]));
+    topology.remove_server(s2);
+    assert_eq!(topology.route(us, s3), None);
+    assert_eq!(topology.servers().collect::<Vec<_>>(), vec![us, s1]);
+  }
+
+  #[test]
+  fn expiry() {
+    let [us, s1, s2] = ids();
+    let start = Instant::now();
+    let mut topology = Topology::new();
+    topology.announce(&[s2, s1], &[], us, start);
+    topology.announce(&[s1], &[], us, start + Duration::from_secs(10));
+    topology.expire(start + Duration::from_secs(15), Duration::from_secs(10));
+    assert!(topology.is_linked(us, s1));
+    assert!(!topology.is_linked(s1, s2));
+    assert_eq!(topology.route(us, s2), None);
+  }
+
+  #[test]
+  fn metrics() {
+    // us - s1 - s2
+    //  |         |
+    // s5 - s4 - s3
+    let [us, s1, s2, s3, s4, s5] = ids();
+    let now = Instant::now();
+    let mut topology = Topology::new();
+    topology.announce(&[s4, s3, s2, s1], &[1, 1, 1, 1], us, now);
+    topology.announce(&[s2, s3, s4, s5], &[1, 1, 10, 10], us, now);
+    assert_eq!(topology.cost(s4, s5), Some(10));
+    // the shortest route is the most expensive
+    assert_eq!(topology.route(us, s4), Some(vec![us, s1, s2, s3, s4]));
+    assert_eq!(topology.route_cost(us, s4), Some(4));
+    assert_eq!(topology.route(us, s5), Some(vec![us, s5]));
+    // the links get cheaper, but never free
+    topology.announce(&[s4, s5], &[1, 0], us, now);
+    assert_eq!(topology.route(us, s4), Some(vec![us, s5, s4]));
+    assert_eq!(topology.route_cost(us, s4), Some(2));
+  }
+
+  #[test]
+  fn dot() {
+    // us - s1 - s2
+    //  |         |
+    //  --- s3 ---
+    // s2 is reached through s1, as it has the smallest id
+    let [us, s1, s2, s3] = ids();
+    let now = Instant::now();
+    let mut topology = Topology::new();
+    topology.announce(&[s2, s1], &[1, 1], us, now);
+    topology.announce(&[s2, s3], &[1, 1], us, now);
+    topology.link(s2, ServerId::from(9), 1, now);
+    topology.unlink(s2, ServerId::from(9));
+    let alice = ClientId::from(1);
+    let dot = topology.dot(us, [(alice, "al\"ice", s2)]);
+    let lines: Vec<_> = dot.lines().collect();
+    let node = |s: ServerId| format!("\"{}\"", s.0);
+    assert_eq!(lines[0], "graph federation {");
+    assert_eq!(lines[lines.len() - 1], "}");
+    assert_eq!(
+      lines[1],
+      format!("  {} [label=\"00000000\", shape=doublecircle];", node(us))
+    );
+    assert!(
+      lines.contains(&format!("  {} [label=\"02000000\\nvia 01000000\"];", node(s2)).as_str())
+    );
+    assert!(lines
+      .contains(&format!("  {} -- {} [label=\"1\", style=bold];", node(s1), node(s2)).as_str()));
+    assert!(lines.contains(&format!("  {} -- {} [label=\"1\"];", node(s2), node(s3)).as_str()));
+    assert!(lines
+      .contains(&format!("  {} -- {} [label=\"1\", style=bold];", node(us), node(s3)).as_str()));
+    assert!(
+      lines.contains(&format!("  \"{}\" [label=\"al\\\"ice\", shape=box];", alice.0).as_str())
+    );
+    assert!(lines.contains(&format!("  \"{}\" -- {} [style=dotted];", alice.0, node(s2)).as_str()));
+    // each link is drawn once
+    assert_eq!(lines.iter().filter(|l| l.contains(" -- ")).count(), 5);
+  }
+
+  #[test]
+  fn propagation() {
+    let [us, s1, s2, s3] = ids();
+    let now = Instant::now();
+    let mut propagation = Propagation::new(us, Duration::from_secs(10));
+    let neighbours = [(s1, 2), (s3, 7)];
+    let announce = |route: Vec<ServerId>, metrics: Vec<u32>| ServerMessage::WeightedAnnounce {
+      route,
+      clients: HashMap::new(),
+      metrics,
+    };
+    // not sent back to s1, and the metric of the link to s1 was missing
+    let out = propagation.propagate(s1, announce(vec![s2, s1], vec![4]), neighbours, now);
+    assert_eq!(
+      out,
+      vec![(s3, announce(vec![s2, s1, us], vec![4, DEFAULT_COST, 7]))]
+    );
+    // a more expensive route is not forwarded, a cheaper one is
+    let out = propagation.propagate(s3, announce(vec![s2, s3], vec![5, 1]), neighbours, now);
+    assert_eq!(out, Vec::new());
+    let out = propagation.propagate(s3, announce(vec![s2, s3], vec![1, 1]), neighbours, now);
+    assert_eq!(out, vec![(s1, announce(vec![s2, s3, us], vec![1, 1, 2]))]);
+    // the best route is refreshed, even if it got more expensive
+    let out = propagation.propagate(s3, announce(vec![s2, s3], vec![9, 9]), neighbours, now);
+    assert_eq!(out.len(), 1);
+    // the other routes are used again once the best one expires
+    let later = now + Duration::from_secs(11);
+    let out = propagation.propagate(s1, announce(vec![s2, s1], vec![4, 4]), neighbours, later);
+    assert_eq!(out.len(), 1);
+
+    // only the withdrawals that follow the best route are forwarded
+    let withdraw = |route: Vec<ServerId>| ServerMessage::Withdraw {
+      route,
+      clients: Vec::new(),
+    };
+    assert_eq!(
+      propagation.propagate(s3, withdraw(vec![s2, s3]), neighbours, later),
+      Vec::new()
+    );
+    assert_eq!(
+      propagation.propagate(s1, withdraw(vec![s2, s1]), neighbours, later),
+      vec![(s3, withdraw(vec![s2, s1, us]))]
+    );
+    assert_eq!(
+      propagation.propagate(s1, withdraw(vec![s2, s1]), neighbours, later),
+      Vec::new()
+    );
+
+    // loops
+    let looped = ServerMessage::Announce {
+      route: vec![s2, us, s1],
+      clients: HashMap::new(),
+    };
+    assert_eq!(
+      propagation.propagate(s1, looped, [(s3, 1)], now),
+      Vec::new()
+    );
+  }
+
+  #[test]
+  fn lost_links() {
+    let [us, s1, s2, s3] = ids();
+    let now = Instant::now();
+    let mut propagation = Propagation::new(us, Duration::from_secs(10));
+    let neighbours = [(s1, 1), (s3, 1)];
+    let announce = |route: Vec<ServerId>| ServerMessage::Announce {
+      route,
+      clients: HashMap::new(),
+    };
+    propagation.propagate(s1, announce(vec![s2, s1]), neighbours, now);
+    propagation.propagate(s3, announce(vec![s3]), neighbours, now);
+
+    // no best route goes through s2 - s3
+    let unlink = |a, b| ServerMessage::Unlink { a, b };
+    assert_eq!(
+      propagation.propagate(s3, unlink(s3, s2), neighbours, now),
+      Vec::new()
+    );
+    // in either direction, and only once
+    assert_eq!(
+      propagation.propagate(s1, unlink(s1, s2), neighbours, now),
+      vec![(s3, unlink(s1, s2))]
+    );
+    assert_eq!(
+      propagation.propagate(s1, unlink(s1, s2), neighbours, now),
+      Vec::new()
+    );
+    // the route to s3 was kept
+    assert_eq!(
+      propagation.propagate(s3, announce(vec![s3]), neighbours, now),
+      vec![(s1, announce(vec![s3, us]))]
+    );
+  }
+}
@@ -0,0 +1,595 @@
+/* In-process federation of servers, for tests.
+
+  A `Simulation` runs several `MessageServer`s and plays the role of the server binary between
+  them: the `Transfer` replies of clients and the `Outgoing` replies of servers are delivered to
+  the next hop, announces and withdrawals are propagated to the other neighbours, and each server
+  announces its local clients when `announce` is called. Messages are delivered in the order they
+  were sent, so that runs are deterministic.
+
+  Links can be cut, one at a time or by partitioning the servers. As in the binary, the servers
+  at both ends of a cut link withdraw each other, and the messages that were in flight on it are
+  lost.
+
+  The servers are `DynMessageServer`s, so that different implementations can be mixed in a
+  federation.
+
+    let mut sim = Simulation::new();
+    let a = sim.add_server::<Server<DefaultChecker>>();
+    let b = sim.add_implementation(Implementation::find("reference").unwrap());
+    sim.link(a, b, 1);
+    let alice = sim.register(a, "alice").await;
+    let bob = sim.register(b, "bob").await;
+    sim.announce().await;
+    sim.send(alice, ClientMessage::Text { dest: bob, content: "hi".into() }).await;
+*/
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::core::{Clock, DefaultChecker, DynAdapter, DynMessageServer, MessageServer, TestClock};
+use crate::messages::{
+  ClientId, ClientMessage, ClientPollReply, ClientReply, ServerId, ServerMessage, ServerReply,
+};
+use crate::routing::Propagation;
+use crate::solutions::reference::ROUTE_TTL;
+use crate::solutions::{Implementation, Settings};
+
+/// number of deliveries after which `run` gives up, as the messages must be looping
+pub const MAX_DELIVERIES: usize = 100_000;
+
+/// a message between two servers
+struct InFlight {
+  from: ServerId,
+  to: ServerId,
+  message: ServerMessage,
+}
+
+pub struct Simulation {
+  clock: TestClock,
+  servers: BTreeMap<ServerId, Box<dyn DynMessageServer>>,
+  /// what each server forwards, as in the binary
+  propagation: BTreeMap<ServerId, Propagation>,
+  /// links that are up, with their cost, stored in both directions
+  links: BTreeMap<(ServerId, ServerId), u32>,
+  /// links that were cut, until they are restored
+  down: BTreeMap<(ServerId, ServerId), u32>,
+  /// the server of each client
+  clients: HashMap<ClientId, ServerId>,
+  inflight: VecDeque<InFlight>,
+  delivered: usize,
+  dropped: usize,
+}
+
+impl Default for Simulation {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Simulation {
+  pub fn new() -> Self {
+    Simulation {
+      clock: TestClock::new(),
+      servers: BTreeMap::new(),
+      propagation: BTreeMap::new(),
+      links: BTreeMap::new(),
+      down: BTreeMap::new(),
+      clients: HashMap::new(),
+      inflight: VecDeque::new(),
+      delivered: 0,
+      dropped: 0,
+    }
+  }
+
+  /// adds a server, without links, the ids are increasing
+  pub fn add_server<M>(&mut self) -> ServerId
+  where
+    M: MessageServer<DefaultChecker> + Send + Sync + 'static,
+  {
+    self
+      .add_with(|id, clock| DynAdapter::boxed(M::with_clock(DefaultChecker::default(), id, clock)))
+  }
+
+  /// adds a server from the registry, with the default settings
+  pub fn add_implementation(&mut self, implementation: &Implementation) -> ServerId {
+    self.add_with_settings(implementation, &Settings::default())
+  }
+
+  /// adds a server from the registry, the clock of the settings is replaced by the simulation one
+  pub fn add_with_settings(
+    &mut self,
+    implementation: &Implementation,
+    settings: &Settings,
+  ) -> ServerId {
+    self.add_with(|id, clock| {
+      let settings = Settings {
+        clock,
+        ..settings.clone()
+      };
+      (implementation.new)(DefaultChecker::default(), id, &settings)
+    })
+  }
+
+  /// adds the server built by `new` from its id and the simulation clock
+  pub fn add_with<F>(&mut self, new: F) -> ServerId
+  where
+    F: FnOnce(ServerId, Arc<dyn Clock>) -> Box<dyn DynMessageServer>,
+  {
+    let id = ServerId::from(self.servers.len() as u128 + 1);
+    let server = new(id, Arc::new(self.clock.clone()));
+    self.servers.insert(id, server);
+    self.propagation.insert(id, Propagation::new(id, ROUTE_TTL));
+    id
+  }
+
+  /// the clock of all the servers
+  pub fn clock(&self) -> &TestClock {
+    &self.clock
+  }
+
+  pub fn server(&self, id: ServerId) -> &dyn DynMessageServer {
+    self.servers[&id].as_ref()
+  }
+
+  pub fn servers(&self) -> impl Iterator<Item = ServerId> + '_ {
+    self.servers.keys().copied()
+  }
+
+  /// links two servers, the cost is given in the announces that go through the link
+  pub fn link(&mut self, a: ServerId, b: ServerId, cost: u32) {
+    assert!(
+      self.servers.contains_key(&a) && self.servers.contains_key(&b),
+      "unknown server"
+    );
+    self.down.remove(&(a, b));
+    self.down.remove(&(b, a));
+    self.links.insert((a, b), cost);
+    self.links.insert((b, a), cost);
+  }
+
+  pub fn is_linked(&self, a: ServerId, b: ServerId) -> bool {
+    self.links.contains_key(&(a, b))
+  }
+
+  /// the servers linked to `server`
+  pub fn neighbours(&self, server: ServerId) -> Vec<ServerId> {
+    self
+      .links
+      .keys()
+      .filter(|(a, _)| *a == server)
+      .map(|(_, b)| *b)
+      .collect()
+  }
+
+  /// Cuts a link, the messages in flight on it are lost, and both ends report it with `Unlink`.
+  /// Returns false if the servers were not linked.
+  pub async fn cut(&mut self, a: ServerId, b: ServerId) -> bool {
+    let Some(cost) = self.links.remove(&(a, b)) else {
+      return false;
+    };
+    self.links.remove(&(b, a));
+    self.down.insert((a, b), cost);
+    self.down.insert((b, a), cost);
+    let before = self.inflight.len();
+    self
+      .inflight
+      .retain(|m| (m.from, m.to) != (a, b) && (m.from, m.to) != (b, a));
+    self.dropped += before - self.inflight.len();
+    for (server, lost) in [(a, b), (b, a)] {
+      let unlink = ServerMessage::Unlink { a: server, b: lost };
+      self.receive(lost, server, unlink).await;
+    }
+    self.run().await;
+    true
+  }
+
+  /// cuts all the links between `group` and the other servers
+  pub async fn partition(&mut self, group: &[ServerId]) {
+    let crossing: Vec<_> = self
+      .links
+      .keys()
+      .filter(|(a, b)| a < b && group.contains(a) != group.contains(b))
+      .copied()
+      .collect();
+    for (a, b) in crossing {
+      self.cut(a, b).await;
+    }
+  }
+
+  /// brings back up a link that was cut, it is used by the next announces
+  pub fn restore(&mut self, a: ServerId, b: ServerId) -> bool {
+    match self.down.get(&(a, b)).copied() {
+      Some(cost) => {
+        self.link(a, b, cost);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// brings back up all the links that were cut
+  pub fn heal(&mut self) {
+    let down: Vec<_> = self.down.iter().map(|(k, v)| (*k, *v)).collect();
+    for ((a, b), cost) in down {
+      self.link(a, b, cost);
+    }
+  }
+
+  /// registers a client on a server
+  pub async fn register(&mut self, server: ServerId, name: &str) -> ClientId {
+    let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let client = self.servers[&server]
+      .register_local_client(ip, name.to_string())
+      .await
+      .expect("the client could not be registered");
+    self.clients.insert(client, server);
+    client
+  }
+
+  /// every server announces its local clients to its neighbours, then the announces are
+  /// propagated until the federation is quiet
+  pub async fn announce(&mut self) {
+    let ids: Vec<_> = self.servers().collect();
+    for id in ids {
+      let mut clients = self.servers[&id].list_users().await;
+      clients.retain(|c, _| self.clients.get(c) == Some(&id));
+      for dest in self.neighbours(id) {
+        let message = ServerMessage::WeightedAnnounce {
+          route: vec![id],
+          clients: clients.clone(),
+          metrics: vec![self.links[&(id, dest)]],
+        };
+        self.send_to(id, dest, message);
+      }
+    }
+    self.run().await;
+  }
+
+  /// a client sends a message, the transfers are delivered until the federation is quiet
+  pub async fn send(&mut self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply> {
+    let server = self.clients[&src];
+    let replies = self.servers[&server].handle_client_message(src, msg).await;
+    for reply in &replies {
+      if let ClientReply::Transfer(nexthop, message) = reply {
+        self.send_to(server, *nexthop, message.clone());
+      }
+    }
+    self.run().await;
+    replies
+  }
+
+  pub async fn poll(&self, client: ClientId) -> ClientPollReply {
+    self.servers[&self.clients[&client]]
+      .client_poll(client)
+      .await
+  }
+
+  /// moves the clock forward and runs the periodic tasks of the servers
+  pub async fn advance(&mut self, delta: Duration) {
+    self.clock.advance(delta);
+    for server in self.servers.values() {
+      server.tick().await;
+    }
+    self.run().await;
+  }
+
+  /// number of messages delivered between servers
+  pub fn delivered(&self) -> usize {
+    self.delivered
+  }
+
+  /// number of messages lost because their link was down, or did not exist
+  pub fn dropped(&self) -> usize {
+    self.dropped
+  }
+
+  fn send_to(&mut self, from: ServerId, to: ServerId, message: ServerMessage) {
+    self.inflight.push_back(InFlight { from, to, message });
+  }
+
+  /// delivers the messages in flight, and the ones they cause, until there are none left
+  pub async fn run(&mut self) {
+    let mut deliveries = 0;
+    while let Some(m) = self.inflight.pop_front() {
+      deliveries += 1;
+      assert!(
+        deliveries <= MAX_DELIVERIES,
+        "the messages between servers never stop"
+      );
+      if !self.is_linked(m.from, m.to) {
+        log::debug!("{} -> {}: no link, dropped {:?}", m.from, m.to, m.message);
+        self.dropped += 1;
+        continue;
+      }
+      self.delivered += 1;
+      self.receive(m.from, m.to, m.message).await;
+    }
+  }
+
+  /// same as `federation::handle_server_message` in the server binary
+  async fn receive(&mut self, from: ServerId, to: ServerId, message: ServerMessage) {
+    let forward = match &message {
+      ServerMessage::Announce { .. }
+      | ServerMessage::WeightedAnnounce { .. }
+      | ServerMessage::Withdraw { .. }
+      | ServerMessage::Unlink { .. } => Some(message.clone()),
+      ServerMessage::Message(_) | ServerMessage::Failure { .. } | ServerMessage::Relay { .. } => {
+        None
+      }
+    };
+    match self.servers[&to].handle_server_message(message).await {
+      ServerReply::Outgoing(outgoing) => {
+        if let Some(message) = forward {
+          let neighbours: Vec<_> = self
+            .neighbours(to)
+            .into_iter()
+            .map(|dest| (dest, self.links[&(to, dest)]))
+            .collect();
+          let now = self.clock.now();
+          let propagation = self.propagation.get_mut(&to).unwrap();
+          for (dest, message) in propagation.propagate(from, message, neighbours, now) {
+            self.send_to(to, dest, message);
+          }
+        }
+        for o in outgoing {
+          self.send_to(to, o.nexthop, ServerMessage::Message(o.message));
+        }
+      }
+      ServerReply::Relay(outgoing) => {
+        for o in outgoing {
+          self.send_to(to, o.nexthop, o.message);
+        }
+      }
+      ServerReply::EmptyRoute => log::warn!("{} -> {}: empty route", from, to),
+      ServerReply::Error(rr) => log::warn!("{} -> {}: {}", from, to, rr),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::solutions::reference::Server;
+
+  type Reference = Server<DefaultChecker>;
+
+  fn text(dest: ClientId, content: &str) -> ClientMessage {
+    ClientMessage::Text {
+      dest,
+      content: content.to_string(),
+    }
+  }
+
+  fn received(src: ClientId, content: &str) -> ClientPollReply {
+    ClientPollReply::Message {
+      src,
+      content: content.to_string(),
+    }
+  }
+
+  /// a - b - c - d - e - f, and a - f when `ring` is set
+  fn servers(sim: &mut Simulation, ring: bool) -> [ServerId; 6] {
+    let ids = std::array::from_fn(|_| sim.add_server::<Reference>());
+    for pair in ids.windows(2) {
+      sim.link(pair[0], pair[1], 1);
+    }
+    if ring {
+      sim.link(ids[5], ids[0], 1);
+    }
+    ids
+  }
+
+  #[test]
+  fn end_to_end() {
+    async_std::task::block_on(async {
+      let mut sim = Simulation::new();
+      let [a, _, _, _, _, f] = servers(&mut sim, false);
+      let alice = sim.register(a, "alice").await;
+      let frank = sim.register(f, "frank").await;
+      sim.announce().await;
+      assert_eq!(sim.server(a).list_users().await.len(), 2);
+      assert_eq!(sim.server(a).route_to(f).await.map(|r| r.len()), Some(6));
+
+      let replies = sim.send(alice, text(frank, "hello")).await;
+      assert!(matches!(replies[..], [ClientReply::Transfer(_, _)]));
+      assert_eq!(sim.poll(frank).await, received(alice, "hello"));
+      sim.send(frank, text(alice, "hi")).await;
+      assert_eq!(sim.poll(alice).await, received(frank, "hi"));
+      assert_eq!(sim.dropped(), 0);
+    });
+  }
+
+  #[test]
+  fn link_drop() {
+    async_std::task::block_on(async {
+      let mut sim = Simulation::new();
+      let [a, b, _, _, _, f] = servers(&mut sim, true);
+      let alice = sim.register(a, "alice").await;
+      let bob = sim.register(b, "bob").await;
+      sim.announce().await;
+      assert_eq!(sim.server(a).route_to(b).await, Some(vec![a, b]));
+
+      assert!(sim.cut(a, b).await);
+      assert!(!sim.cut(a, b).await);
+      // b is still reachable the other way around
+      assert!(sim.server(a).list_users().await.contains_key(&bob));
+      assert!(sim.server(f).list_users().await.contains_key(&bob));
+      sim.announce().await;
+      assert_eq!(sim.server(a).route_to(b).await.map(|r| r.len()), Some(6));
+      sim.send(alice, text(bob, "around")).await;
+      assert_eq!(sim.poll(bob).await, received(alice, "around"));
+
+      assert!(sim.restore(a, b));
+      sim.announce().await;
+      assert_eq!(sim.server(f).route_to(b).await, Some(vec![f, a, b]));
+    });
+  }
+
+  #[test]
+  fn full_mesh() {
+    async_std::task::block_on(async {
+      const N: usize = 8;
+      let mut sim = Simulation::new();
+      let ids: Vec<_> = (0..N).map(|_| sim.add_server::<Reference>()).collect();
+      for (i, &a) in ids.iter().enumerate() {
+        for &b in &ids[i + 1..] {
+          sim.link(a, b, 1);
+        }
+      }
+      for (i, &id) in ids.iter().enumerate() {
+        sim.register(id, &format!("user{}", i)).await;
+      }
+      // each announce reaches every server directly, and is forwarded once by each of them
+      for round in 1..=3 {
+        sim.announce().await;
+        assert!(sim.delivered() <= round * N * (N - 1) * (N - 1));
+      }
+      for &id in &ids {
+        assert_eq!(sim.server(id).list_users().await.len(), N);
+      }
+      assert_eq!(
+        sim.server(ids[1]).route_to(ids[0]).await,
+        Some(vec![ids[1], ids[0]])
+      );
+      assert_eq!(sim.dropped(), 0);
+    });
+  }
+
+  #[test]
+  fn partition() {
+    async_std::task::block_on(async {
+      let mut sim = Simulation::new();
+      let [a, b, c, d, e, f] = servers(&mut sim, true);
+      let alice = sim.register(a, "alice").await;
+      let dave = sim.register(d, "dave").await;
+      sim.announce().await;
+
+      sim.partition(&[a, b, c]).await;
+      assert!(!sim.is_linked(c, d) && !sim.is_linked(f, a));
+      sim.announce().await;
+      assert_eq!(sim.server(a).route_to(d).await, None);
+      assert_eq!(sim.server(e).route_to(d).await, Some(vec![e, d]));
+      // dave is not known anymore, the message waits for him
+      let replies = sim.send(alice, text(dave, "are you there?")).await;
+      assert_eq!(replies, vec![ClientReply::Delayed]);
+      assert_eq!(sim.poll(dave).await, ClientPollReply::Nothing);
+
+      sim.heal();
+      sim.announce().await;
+      assert_eq!(sim.poll(dave).await, received(alice, "are you there?"));
+      assert_eq!(sim.server(b).route_to(d).await, Some(vec![b, c, d]));
+    });
+  }
+
+  #[test]
+  fn lost_in_flight() {
+    async_std::task::block_on(async {
+      let mut sim = Simulation::new();
+      let [a, b] = [sim.add_server::<Reference>(), sim.add_server::<Reference>()];
+      sim.link(a, b, 1);
+      let alice = sim.register(a, "alice").await;
+      let bob = sim.register(b, "bob").await;
+      sim.announce().await;
+      let delivered = sim.delivered();
+
+      // the transfer is sent, but the link goes down before it is delivered
+      let replies = sim.servers[&a]
+        .handle_client_message(alice, text(bob, "lost"))
+        .await;
+      let [ClientReply::Transfer(nexthop, message)] = &replies[..] else {
+        panic!("unexpected replies {:?}", replies);
+      };
+      sim.send_to(a, *nexthop, message.clone());
+      sim.cut(a, b).await;
+      assert_eq!(sim.dropped(), 1);
+      assert_eq!(sim.delivered(), delivered);
+      assert_eq!(sim.poll(bob).await, ClientPollReply::Nothing);
+    });
+  }
+
+  #[test]
+  fn mixed_servers() {
+    async_std::task::block_on(async {
+      let mut sim = Simulation::new();
+      let a = sim.add_server::<Reference>();
+      // routes are forgotten sooner on b
+      let settings = Settings {
+        route_ttl: Duration::from_secs(5),
+        ..Settings::default()
+      };
+      let b = sim.add_with_settings(Implementation::find("reference").unwrap(), &settings);
+      // c is shared with the test
+      let mut shared = None;
+      let c = sim.add_with(|id, clock| {
+        let server = Reference::with_clock(DefaultChecker::default(), id, clock);
+        let server = Arc::new(DynAdapter::new(server));
+        shared = Some(server.clone());
+        Box::new(server)
+      });
+      let shared = shared.unwrap();
+      sim.link(a, b, 1);
+      sim.link(b, c, 1);
+      let alice = sim.register(a, "alice").await;
+      let carol = sim.register(c, "carol").await;
+      sim.announce().await;
+      assert!(sim
+        .servers()
+        .all(|s| sim.server(s).group_name() == "reference"));
+
+      sim.send(alice, text(carol, "hello")).await;
+      assert_eq!(shared.client_poll(carol).await, received(alice, "hello"));
+
+      sim.advance(Duration::from_secs(10)).await;
+      assert_eq!(sim.server(a).route_to(c).await, Some(vec![a, b, c]));
+      assert_eq!(sim.server(b).route_to(c).await, None);
+    });
+  }
+
+  #[test]
+  fn layered_servers() {
+    use crate::messages::ClientError;
+    use crate::middleware::{Denylist, Latency, LayerExt, Tracing};
+
+    async_std::task::block_on(async {
+      let mut sim = Simulation::new();
+      let a = sim.add_server::<Reference>();
+      // the messages between a and c go through the layers of b
+      let denylist = Denylist::default();
+      let latency = Latency::default();
+      let b = sim.add_with(|id, clock| {
+        let server = Reference::with_clock(DefaultChecker::default(), id, clock);
+        let server = DynAdapter::boxed(server)
+          .layer(denylist.clone())
+          .layer(latency.clone())
+          .layer(Tracing);
+        Box::new(server)
+      });
+      let c = sim.add_server::<Reference>();
+      sim.link(a, b, 1);
+      sim.link(b, c, 1);
+      let alice = sim.register(a, "alice").await;
+      let bob = sim.register(b, "bob").await;
+      let carol = sim.register(c, "carol").await;
+      sim.announce().await;
+      assert_eq!(sim.server(a).list_users().await.len(), 3);
+
+      sim.send(alice, text(carol, "hello")).await;
+      assert_eq!(sim.poll(carol).await, received(alice, "hello"));
+      let histograms = latency.histograms();
+      assert!(histograms["handle_server_message"].count() > 0);
+
+      denylist.deny(bob);
+      let replies = sim.send(bob, text(alice, "hi")).await;
+      assert_eq!(
+        replies,
+        vec![ClientReply::Error(ClientError::UnknownClient)]
+      );
+      assert_eq!(sim.poll(alice).await, ClientPollReply::Nothing);
+      sim.send(carol, text(bob, "hi")).await;
+      assert_eq!(sim.poll(bob).await, ClientPollReply::Nothing);
+      denylist.allow(bob);
+      assert_eq!(sim.poll(bob).await, received(carol, "hi"));
+    });
+  }
+}
@@ -1 +1,111 @@
+/* Implementations of `MessageServer`, one per file.
+
+  Each implementation is listed in `IMPLEMENTATIONS`, under its `GROUP_NAME`, so that the server
+  binary can run any of them with `--impl <name>`. The ones that are not finished yet are listed
+  too, but the server refuses to run them.
+*/
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::core::{
+  Clock, DefaultChecker, DynAdapter, DynMessageServer, MessageServer, SystemClock,
+};
+use crate::messages::ServerId;
+
+pub mod reference;
 pub mod sample;
+
+/// settings of the servers created from the registry, each implementation uses the ones it
+/// supports
+#[derive(Clone)]
+pub struct Settings {
+  pub clock: Arc<dyn Clock>,
+  pub route_ttl: Duration,
+  pub delayed_ttl: Duration,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Settings {
+      clock: Arc::new(SystemClock),
+      route_ttl: reference::ROUTE_TTL,
+      delayed_ttl: reference::DELAYED_TTL,
+    }
+  }
+}
+
+/// an implementation of `MessageServer` that can be chosen at runtime
+pub struct Implementation {
+  /// the `GROUP_NAME` of the implementation
+  pub name: &'static str,
+  /// false if the implementation is not finished
+  pub ready: bool,
+  pub new: fn(DefaultChecker, ServerId, &Settings) -> Box<dyn DynMessageServer>,
+}
+
+impl Implementation {
+  /// an implementation that only uses the clock of the settings
+  pub const fn of<M: MessageServer<DefaultChecker> + Send + Sync + 'static>() -> Self {
+    Implementation {
+      name: M::GROUP_NAME,
+      ready: true,
+      new: |checker, id, settings| {
+        DynAdapter::boxed(M::with_clock(checker, id, settings.clock.clone()))
+      },
+    }
+  }
+
+  /// an implementation that is not finished
+  pub const fn unfinished<M: MessageServer<DefaultChecker> + Send + Sync + 'static>() -> Self {
+    Implementation {
+      ready: false,
+      ..Self::of::<M>()
+    }
+  }
+
+  /// finds an implementation by name
+  pub fn find(name: &str) -> Option<&'static Implementation> {
+    IMPLEMENTATIONS.iter().find(|i| i.name == name)
+  }
+}
+
+/// all the implementations, the first one is the default
+pub const IMPLEMENTATIONS: &[Implementation] = &[
+  Implementation {
+    name: <reference::Server<DefaultChecker> as MessageServer<DefaultChecker>>::GROUP_NAME,
+    ready: true,
+    new: |checker, id, settings| {
+      let server = reference::Server::with_clock(checker, id, settings.clock.clone())
+        .with_route_ttl(settings.route_ttl)
+        .with_delayed_ttl(settings.delayed_ttl);
+      DynAdapter::boxed(server)
+    },
+  },
+  // every method is still `todo!()`
+  Implementation::unfinished::<sample::Server<DefaultChecker>>(),
+];
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn registry() {
+    let names: Vec<_> = IMPLEMENTATIONS.iter().map(|i| i.name).collect();
+    assert_eq!(names[0], "reference");
+    for name in &names {
+      assert_eq!(names.iter().filter(|n| *n == name).count(), 1, "{}", name);
+    }
+    let reference = Implementation::find("reference").unwrap();
+    let server = (reference.new)(
+      DefaultChecker::default(),
+      ServerId::default(),
+      &Settings::default(),
+    );
+    assert_eq!(server.group_name(), "reference");
+    assert!(reference.ready);
+    let sample = <sample::Server<DefaultChecker> as MessageServer<DefaultChecker>>::GROUP_NAME;
+    assert!(!Implementation::find(sample).unwrap().ready);
+    assert!(Implementation::find("nope").is_none());
+  }
+}
@@ -0,0 +1,1015 @@
+/* Reference implementation of the message server.
+
+  Routes are learned from announces, see the `routing` module. Links and remote clients are
+  timestamped when they are announced, and are forgotten when they are withdrawn, or when they
+  are not announced again within the route lifetime. Expired entries are only removed by `tick`,
+  so that handling messages only takes read locks on the routes.
+
+  Messages to several remote clients are split by next hop: a single `FullyQualifiedMessage` is
+  sent to each neighbour, with the recipients that are reached through it. When a client sends
+  such a message, the `Transfer` is the reply of the first of these recipients, and the others
+  get a `Grouped` reply. Messages from other servers are forwarded as `ServerMessage::Relay`, with the
+  servers they went through, so that they do not loop.
+
+  The local clients are split in shards, each with its own lock, so that the clients of
+  different shards can poll and receive messages at the same time.
+*/
+use async_std::sync::RwLock;
+use async_trait::async_trait;
+use futures::{select, FutureExt};
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  net::IpAddr,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use crate::{
+  core::{Clock, MessageServer, SpamChecker, SystemClock, MAILBOX_SIZE, MAX_HOPS},
+  messages::{
+    ClientError, ClientId, ClientMessage, ClientPollReply, ClientReply, DelayedError,
+    FullyQualifiedMessage, Outgoing, Sequence, ServerId, ServerMessage, ServerReply,
+  },
+  routing::Topology,
+};
+
+/// how long routes and remote clients are kept when they are not announced again
+pub const ROUTE_TTL: Duration = Duration::from_secs(60);
+
+/// how long messages for unknown clients are kept before giving up
+pub const DELAYED_TTL: Duration = Duration::from_secs(600);
+
+/// number of shards of the local clients
+const SHARDS: usize = 32;
+
+struct LocalClient {
+  name: String,
+  /// last sequence number seen
+  seqid: u128,
+  mailbox: VecDeque<ClientPollReply>,
+}
+
+/// the local clients, sharded by id
+struct Clients {
+  shards: Vec<RwLock<HashMap<ClientId, LocalClient>>>,
+}
+
+impl Clients {
+  fn new() -> Self {
+    Clients {
+      shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+    }
+  }
+
+  /// the shard of a client, the ids are random so their low bits are evenly distributed
+  fn shard(&self, id: &ClientId) -> &RwLock<HashMap<ClientId, LocalClient>> {
+    &self.shards[(u128::from(id) % SHARDS as u128) as usize]
+  }
+
+  async fn contains(&self, id: &ClientId) -> bool {
+    self.shard(id).read().await.contains_key(id)
+  }
+
+  /// the names of all the clients, the shards are read one at a time
+  async fn names(&self) -> Vec<(ClientId, String)> {
+    let mut names = Vec::new();
+    for shard in &self.shards {
+      names.extend(
+        shard
+          .read()
+          .await
+          .iter()
+          .map(|(id, c)| (*id, c.name.clone())),
+      );
+    }
+    names
+  }
+}
+
+/// a message for an unknown client
+struct DelayedMessage {
+  src: ClientId,
+  content: String,
+  since: Instant,
+}
+
+struct RemoteClient {
+  name: String,
+  server: ServerId,
+  seen: Instant,
+}
+
+pub struct Server<C: SpamChecker> {
+  checker: C,
+  id: ServerId,
+  clock: Arc<dyn Clock>,
+  route_ttl: Duration,
+  delayed_ttl: Duration,
+  // locks are always taken in the order of the fields
+  clients: Clients,
+  remote: RwLock<HashMap<ClientId, RemoteClient>>,
+  topology: RwLock<Topology>,
+  /// messages for unknown clients, by recipient
+  delayed: RwLock<HashMap<ClientId, Vec<DelayedMessage>>>,
+}
+
+#[async_trait]
+impl<C: SpamChecker + Send + Sync> MessageServer<C> for Server<C> {
+  const GROUP_NAME: &'static str = "reference";
+
+  fn new(checker: C, id: ServerId) -> Self {
+    Self::with_clock(checker, id, Arc::new(SystemClock))
+  }
+
+  fn with_clock(checker: C, id: ServerId, clock: Arc<dyn Clock>) -> Self {
+    Server {
+      checker,
+      id,
+      clock,
+      route_ttl: ROUTE_TTL,
+      delayed_ttl: DELAYED_TTL,
+      clients: Clients::new(),
+      remote: RwLock::new(HashMap::new()),
+      topology: RwLock::new(Topology::default()),
+      delayed: RwLock::new(HashMap::new()),
+    }
+  }
+
+  async fn register_local_client(&self, src_ip: IpAddr, name: String) -> Option<ClientId> {
+    // the client is a spammer as soon as one of the checks says so
+    let spammer = {
+      let mut ip = self.checker.is_ip_spammer(&src_ip).fuse();
+      let mut user = self.checker.is_user_spammer(&name).fuse();
+      select! {
+        r = ip => r || user.await,
+        r = user => r || ip.await,
+      }
+    };
+    if spammer {
+      return None;
+    }
+    let id = ClientId::from(Uuid::new_v4());
+    self.clients.shard(&id).write().await.insert(
+      id,
+      LocalClient {
+        name,
+        seqid: 0,
+        mailbox: VecDeque::new(),
+      },
+    );
+    Some(id)
+  }
+
+  async fn list_users(&self) -> HashMap<ClientId, String> {
+    let mut users: HashMap<ClientId, String> = self.clients.names().await.into_iter().collect();
+    for (id, c) in self.remote.read().await.iter() {
+      users.insert(*id, c.name.clone());
+    }
+    users
+  }
+
+  async fn handle_sequenced_message<A: Send>(
+    &self,
+    sequence: Sequence<A>,
+  ) -> Result<A, ClientError> {
+    let mut shard = self.clients.shard(&sequence.src).write().await;
+    let client = shard
+      .get_mut(&sequence.src)
+      .ok_or(ClientError::UnknownClient)?;
+    if sequence.seqid <= client.seqid {
+      return Err(ClientError::InternalError);
+    }
+    client.seqid = sequence.seqid;
+    Ok(sequence.content)
+  }
+
+  async fn client_poll(&self, client: ClientId) -> ClientPollReply {
+    self
+      .clients
+      .shard(&client)
+      .write()
+      .await
+      .get_mut(&client)
+      .and_then(|c| c.mailbox.pop_front())
+      .unwrap_or(ClientPollReply::Nothing)
+  }
+
+  async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply> {
+    let (dests, content) = match msg {
+      ClientMessage::Text { dest, content } => (vec![dest], content),
+      ClientMessage::MText { dest, content } => (dest, content),
+    };
+    let nexthops = self.nexthops().await;
+    let mut replies: Vec<ClientReply> = Vec::with_capacity(dests.len());
+    // the reply of each recipient, repeated recipients get the same one
+    let mut seen: HashMap<ClientId, usize> = HashMap::new();
+    // the index of the reply of the first recipient of each group
+    let mut first = Vec::new();
+    let mut groups = Vec::new();
+    for dest in dests {
+      if let Some(&i) = seen.get(&dest) {
+        replies.push(replies[i].clone());
+        continue;
+      }
+      seen.insert(dest, replies.len());
+      if self.clients.contains(&dest).await {
+        replies.push(self.deliver(src, dest, content.clone()).await);
+        continue;
+      }
+      let server = self.remote.read().await.get(&dest).map(|c| c.server);
+      match server.and_then(|server| Some((server, *nexthops.get(&server)?))) {
+        Some((server, nexthop)) => {
+          if group(&mut groups, nexthop, (dest, server)) {
+            first.push(replies.len());
+          }
+          // the first one is replaced by the transfer below
+          replies.push(ClientReply::Grouped(nexthop));
+        }
+        None => {
+          // the client might be announced later
+          self
+            .delayed
+            .write()
+            .await
+            .entry(dest)
+            .or_default()
+            .push(DelayedMessage {
+              src,
+              content: content.clone(),
+              since: self.clock.now(),
+            });
+          replies.push(ClientReply::Delayed);
+        }
+      }
+    }
+    for (i, (nexthop, dsts)) in first.into_iter().zip(groups) {
+      let message = FullyQualifiedMessage {
+        src,
+        srcsrv: self.id,
+        dsts,
+        content: content.clone(),
+      };
+      replies[i] = ClientReply::Transfer(nexthop, ServerMessage::Message(message));
+    }
+    replies
+  }
+
+  async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply {
+    match msg {
+      ServerMessage::Announce { route, clients } => self.announce(route, clients, Vec::new()).await,
+      ServerMessage::Withdraw { route, clients } => {
+        let Some(origin) = route.first().copied() else {
+          return ServerReply::EmptyRoute;
+        };
+        if origin == self.id {
+          return ServerReply::Outgoing(Vec::new());
+        }
+        if clients.is_empty() {
+          self.remote.write().await.retain(|_, c| c.server != origin);
+          self.topology.write().await.remove_server(origin);
+        } else {
+          self
+            .remote
+            .write()
+            .await
+            .retain(|id, c| c.server != origin || !clients.contains(id));
+        }
+        ServerReply::Outgoing(Vec::new())
+      }
+      ServerMessage::Unlink { a, b } => {
+        let mut topology = self.topology.write().await;
+        topology.unlink(a, b);
+        // only the clients of the servers that cannot be reached any more go away
+        let nexthops = topology.nexthops(self.id);
+        self
+          .remote
+          .write()
+          .await
+          .retain(|_, c| nexthops.contains_key(&c.server));
+        ServerReply::Outgoing(Vec::new())
+      }
+      ServerMessage::Message(fqm) => self.relay(fqm, Vec::new()).await,
+      ServerMessage::Relay { message, path } => self.relay(message, path).await,
+      ServerMessage::WeightedAnnounce {
+        route,
+        clients,
+        metrics,
+      } => self.announce(route, clients, metrics).await,
+      ServerMessage::Failure {
+        src,
+        srcsrv,
+        error,
+        path,
+      } => relayed(
+        self
+          .fail(src, srcsrv, error, path)
+          .await
+          .into_iter()
+          .collect(),
+      ),
+    }
+  }
+
+  async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>> {
+    self.topology.read().await.route(self.id, destination)
+  }
+
+  async fn tick(&self) {
+    self.expire().await;
+    self.sweep().await;
+  }
+
+  async fn topology_dot(&self) -> Option<String> {
+    let clients = self.clients.names().await;
+    let remote = self.remote.read().await;
+    let users = clients
+      .iter()
+      .map(|(id, name)| (*id, name.as_str(), self.id))
+      .chain(
+        remote
+          .iter()
+          .map(|(id, c)| (*id, c.name.as_str(), c.server)),
+      );
+    Some(self.topology.read().await.dot(self.id, users))
+  }
+}
+
+impl<C: SpamChecker + Send + Sync> Server<C> {
+  /// sets how long routes and remote clients are kept when they are not announced again
+  pub fn with_route_ttl(mut self, ttl: Duration) -> Self {
+    self.route_ttl = ttl;
+    self
+  }
+
+  /// sets how long messages for unknown clients are kept
+  pub fn with_delayed_ttl(mut self, ttl: Duration) -> Self {
+    self.delayed_ttl = ttl;
+    self
+  }
+
+  /// Drops the messages for unknown clients that are older than the retention period, and
+  /// tells their senders with a `DelayedError::UnknownRecipient`.
+  async fn sweep(&self) {
+    let now = self.clock.now();
+    let mut expired = Vec::new();
+    self.delayed.write().await.retain(|dest, messages| {
+      messages.retain(|m| {
+        let keep = now.duration_since(m.since) <= self.delayed_ttl;
+        if !keep {
+          expired.push((m.src, *dest));
+        }
+        keep
+      });
+      !messages.is_empty()
+    });
+    for (src, dest) in expired {
+      log::debug!("dropping delayed message from {} to {}", src, dest);
+      let error = DelayedError::UnknownRecipient(dest);
+      // the delayed messages are from local clients, so no failure is sent
+      self.fail(src, self.id, error, Vec::new()).await;
+    }
+  }
+
+  /// forgets the routes and remote clients that were not announced recently
+  async fn expire(&self) {
+    let now = self.clock.now();
+    let ttl = self.route_ttl;
+    self
+      .remote
+      .write()
+      .await
+      .retain(|_, c| now.duration_since(c.seen) <= ttl);
+    self.topology.write().await.expire(now, ttl);
+  }
+
+  /// learns the route and clients of an announce, and sends the messages waiting for them
+  async fn announce(
+    &self,
+    route: Vec<ServerId>,
+    clients: HashMap<ClientId, String>,
+    metrics: Vec<u32>,
+  ) -> ServerReply {
+    let Some(origin) = route.first().copied() else {
+      return ServerReply::EmptyRoute;
+    };
+    let now = self.clock.now();
+    let mut topology = self.topology.write().await;
+    topology.announce(&route, &metrics, self.id, now);
+    drop(topology);
+    if origin == self.id {
+      return ServerReply::Outgoing(Vec::new());
+    }
+    let mut remote = self.remote.write().await;
+    for (id, name) in &clients {
+      remote.insert(
+        *id,
+        RemoteClient {
+          name: name.clone(),
+          server: origin,
+          seen: now,
+        },
+      );
+    }
+    drop(remote);
+    let mut outgoing = Vec::new();
+    for id in clients.keys() {
+      let waiting = self.delayed.write().await.remove(id);
+      for delayed in waiting.into_iter().flatten() {
+        let message = FullyQualifiedMessage {
+          src: delayed.src,
+          srcsrv: self.id,
+          dsts: vec![(*id, origin)],
+          content: delayed.content,
+        };
+        match self.nexthop(origin).await {
+          Some(nexthop) => outgoing.push(Outgoing { nexthop, message }),
+          None => log::warn!("no route to {}, dropping message for {}", origin, id),
+        }
+      }
+    }
+    ServerReply::Outgoing(outgoing)
+  }
+
+  /// Delivers a message from another server to the local recipients, and forwards it to the
+  /// others, with our id added to its path. Messages that loop are dropped and reported.
+  async fn relay(&self, fqm: FullyQualifiedMessage, mut path: Vec<ServerId>) -> ServerReply {
+    let mut outgoing = Vec::new();
+    // a message that already went through us is in a loop
+    let looped = fqm.srcsrv == self.id || path.contains(&self.id);
+    let nexthops = self.nexthops().await;
+    let mut seen = HashSet::new();
+    let mut groups = Vec::new();
+    for (dest, server) in fqm.dsts {
+      if !seen.insert(dest) {
+        continue;
+      }
+      if server == self.id {
+        if self.deliver(fqm.src, dest, fqm.content.clone()).await != ClientReply::Delivered {
+          log::warn!("could not deliver message from {} to {}", fqm.src, dest);
+        }
+        continue;
+      }
+      match nexthops.get(&server) {
+        Some(nexthop) if !looped && path.len() < MAX_HOPS => {
+          group(&mut groups, *nexthop, (dest, server));
+        }
+        _ => {
+          log::warn!("dropping message from {} to {}", fqm.src, dest);
+          let error = DelayedError::Unreachable(dest);
+          outgoing.extend(self.fail(fqm.src, fqm.srcsrv, error, Vec::new()).await);
+        }
+      }
+    }
+    path.push(self.id);
+    for (nexthop, dsts) in groups {
+      let message = FullyQualifiedMessage {
+        src: fqm.src,
+        srcsrv: fqm.srcsrv,
+        dsts,
+        content: fqm.content.clone(),
+      };
+      outgoing.push(Outgoing {
+        nexthop,
+        message: ServerMessage::Relay {
+          message,
+          path: path.clone(),
+        },
+      });
+    }
+    relayed(outgoing)
+  }
+
+  /// stores a message in the mailbox of a local client
+  async fn deliver(&self, src: ClientId, dest: ClientId, content: String) -> ClientReply {
+    let mut shard = self.clients.shard(&dest).write().await;
+    let Some(client) = shard.get_mut(&dest) else {
+      return ClientReply::Delayed;
+    };
+    if client.mailbox.len() >= MAILBOX_SIZE {
+      return ClientReply::Error(ClientError::BoxFull(dest));
+    }
+    client
+      .mailbox
+      .push_back(ClientPollReply::Message { src, content });
+    ClientReply::Delivered
+  }
+
+  /// the neighbour to send messages for `server` to
+  async fn nexthop(&self, server: ServerId) -> Option<ServerId> {
+    self.route_to(server).await?.get(1).copied()
+  }
+
+  /// the neighbour to send messages to, for each reachable server
+  async fn nexthops(&self) -> HashMap<ServerId, ServerId> {
+    self.topology.read().await.nexthops(self.id)
+  }
+
+  /// Reports an error to `src`, either directly if it is a local client, or by sending a failure
+  /// to its server. Failures that loop are dropped, and do not cause another failure.
+  async fn fail(
+    &self,
+    src: ClientId,
+    srcsrv: ServerId,
+    error: DelayedError,
+    mut path: Vec<ServerId>,
+  ) -> Option<Outgoing<ServerMessage>> {
+    if srcsrv == self.id {
+      let mut shard = self.clients.shard(&src).write().await;
+      match shard.get_mut(&src) {
+        Some(client) if client.mailbox.len() < MAILBOX_SIZE => client
+          .mailbox
+          .push_back(ClientPollReply::DelayedError(error)),
+        _ => log::warn!("could not report {:?} to {}", error, src),
+      }
+      return None;
+    }
+    if path.contains(&self.id) || path.len() >= MAX_HOPS {
+      log::warn!("dropping failure for {}: {:?}", src, error);
+      return None;
+    }
+    path.push(self.id);
+    let nexthop = self.nexthop(srcsrv).await?;
+    Some(Outgoing {
+      nexthop,
+      message: ServerMessage::Failure {
+        src,
+        srcsrv,
+        error,
+        path,
+      },
+    })
+  }
+}
+
+/// the reply for relayed messages, the replies without any message are `Outgoing`, like the
+/// replies to announces
+fn relayed(outgoing: Vec<Outgoing<ServerMessage>>) -> ServerReply {
+  if outgoing.is_empty() {
+    ServerReply::Outgoing(Vec::new())
+  } else {
+    ServerReply::Relay(outgoing)
+  }
+}
+
+/// Adds a recipient to the group of its next hop, so that a single message is sent to each
+/// neighbour. Returns true if a new group was created.
+fn group(
+  groups: &mut Vec<(ServerId, Vec<(ClientId, ServerId)>)>,
+  nexthop: ServerId,
+  dst: (ClientId, ServerId),
+) -> bool {
+  match groups.iter_mut().find(|(n, _)| *n == nexthop) {
+    Some((_, dsts)) => {
+      dsts.push(dst);
+      false
+    }
+    None => {
+      groups.push((nexthop, vec![dst]));
+      true
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::core::TestClock;
+  use crate::testing::{test_message_server, test_weighted_routing, TestChecker};
+
+  use super::*;
+
+  #[test]
+  fn tester() {
+    test_message_server::<Server<TestChecker>>();
+  }
+
+  #[test]
+  fn weighted_routing() {
+    test_weighted_routing::<Server<TestChecker>>();
+  }
+
+  fn announce(route: Vec<ServerId>, clients: &[ClientId]) -> ServerMessage {
+    ServerMessage::Announce {
+      route,
+      clients: clients.iter().map(|c| (*c, "remote".to_string())).collect(),
+    }
+  }
+
+  #[test]
+  fn route_expiry() {
+    async_std::task::block_on(async {
+      let sid = ServerId::default();
+      let clock = TestClock::new();
+      let server: Server<TestChecker> =
+        Server::with_clock(TestChecker::default(), sid, Arc::new(clock.clone()))
+          .with_route_ttl(Duration::from_secs(60));
+      let (s1, s2) = (ServerId::from(1), ServerId::from(2));
+      let (c1, c2) = (ClientId::default(), ClientId::default());
+      server
+        .handle_server_message(announce(vec![s2, s1], &[c2]))
+        .await;
+      clock.advance(Duration::from_secs(40));
+      // s1 is announced again, but not s2
+      server
+        .handle_server_message(announce(vec![s1], &[c1]))
+        .await;
+      clock.advance(Duration::from_secs(40));
+      // the routes are only forgotten on ticks
+      assert!(server.route_to(s2).await.is_some());
+      server.tick().await;
+      assert_eq!(server.route_to(s1).await, Some(vec![sid, s1]));
+      assert_eq!(server.route_to(s2).await, None);
+      let users = server.list_users().await;
+      assert_eq!(users, HashMap::from([(c1, "remote".to_string())]));
+    })
+  }
+
+  #[test]
+  fn withdraw() {
+    async_std::task::block_on(async {
+      let sid = ServerId::default();
+      let server: Server<TestChecker> = Server::new(TestChecker::default(), sid);
+      let (s1, s2) = (ServerId::from(1), ServerId::from(2));
+      let (c1, c2, c3) = (
+        ClientId::default(),
+        ClientId::default(),
+        ClientId::default(),
+      );
+      server
+        .handle_server_message(announce(vec![s1], &[c1]))
+        .await;
+      server
+        .handle_server_message(announce(vec![s2, s1], &[c2, c3]))
+        .await;
+
+      let r = server
+        .handle_server_message(ServerMessage::Withdraw {
+          route: vec![s2, s1],
+          clients: vec![c3],
+        })
+        .await;
+      assert_eq!(r, ServerReply::Outgoing(Vec::new()));
+      assert_eq!(server.list_users().await.len(), 2);
+      assert_eq!(server.route_to(s2).await, Some(vec![sid, s1, s2]));
+
+      server
+        .handle_server_message(ServerMessage::Withdraw {
+          route: vec![s2, s1],
+          clients: Vec::new(),
+        })
+        .await;
+      assert_eq!(server.route_to(s2).await, None);
+      assert_eq!(server.route_to(s1).await, Some(vec![sid, s1]));
+      let users = server.list_users().await;
+      assert_eq!(users, HashMap::from([(c1, "remote".to_string())]));
+
+      let r = server
+        .handle_server_message(ServerMessage::Withdraw {
+          route: Vec::new(),
+          clients: Vec::new(),
+        })
+        .await;
+      assert_eq!(r, ServerReply::EmptyRoute);
+    })
+  }
+
+  #[test]
+  fn unlink() {
+    async_std::task::block_on(async {
+      let sid = ServerId::default();
+      let server: Server<TestChecker> = Server::new(TestChecker::default(), sid);
+      let (s1, s2, s3) = (ServerId::from(1), ServerId::from(2), ServerId::from(3));
+      let (c1, c2) = (ClientId::default(), ClientId::default());
+      server
+        .handle_server_message(announce(vec![s1], &[c1]))
+        .await;
+      server
+        .handle_server_message(announce(vec![s2, s1], &[c2]))
+        .await;
+      server
+        .handle_server_message(announce(vec![s2, s3], &[c2]))
+        .await;
+
+      // s2 is still reachable through s3
+      let r = server
+        .handle_server_message(ServerMessage::Unlink { a: s2, b: s1 })
+        .await;
+      assert_eq!(r, ServerReply::Outgoing(Vec::new()));
+      assert_eq!(server.route_to(s2).await, Some(vec![sid, s3, s2]));
+      assert_eq!(server.list_users().await.len(), 2);
+
+      server
+        .handle_server_message(ServerMessage::Unlink { a: sid, b: s3 })
+        .await;
+      assert_eq!(server.route_to(s2).await, None);
+      assert_eq!(server.route_to(s1).await, Some(vec![sid, s1]));
+      let users = server.list_users().await;
+      assert_eq!(users, HashMap::from([(c1, "remote".to_string())]));
+    })
+  }
+
+  #[test]
+  fn loops() {
+    async_std::task::block_on(async {
+      let sid = ServerId::default();
+      let server: Server<TestChecker> = Server::new(TestChecker::default(), sid);
+      let (s1, s2, s3) = (ServerId::from(1), ServerId::from(2), ServerId::from(3));
+      let (src, dest) = (ClientId::default(), ClientId::default());
+      server
+        .handle_server_message(announce(vec![s2, s1], &[dest]))
+        .await;
+      server
+        .handle_server_message(announce(vec![s3, s1], &[src]))
+        .await;
+      let message = |path: Vec<ServerId>| ServerMessage::Relay {
+        message: FullyQualifiedMessage {
+          src,
+          srcsrv: s3,
+          dsts: vec![(dest, s2)],
+          content: "loop".to_string(),
+        },
+        path,
+      };
+      let failure = ServerReply::Relay(vec![Outgoing {
+        nexthop: s1,
+        message: ServerMessage::Failure {
+          src,
+          srcsrv: s3,
+          error: DelayedError::Unreachable(dest),
+          path: vec![sid],
+        },
+      }]);
+
+      // forwarded messages get our id
+      let r = server.handle_server_message(message(vec![s1])).await;
+      let expected = ServerReply::Relay(vec![Outgoing {
+        nexthop: s1,
+        message: message(vec![s1, sid]),
+      }]);
+      assert_eq!(r, expected);
+
+      // already went through us
+      let r = server
+        .handle_server_message(message(vec![s1, sid, s1]))
+        .await;
+      assert_eq!(r, failure);
+
+      // too many hops
+      let r = server
+        .handle_server_message(message((0..MAX_HOPS as u128).map(ServerId::from).collect()))
+        .await;
+      assert_eq!(r, failure);
+
+      // failures that loop are dropped
+      let r = server
+        .handle_server_message(ServerMessage::Failure {
+          src,
+          srcsrv: s3,
+          error: DelayedError::Unreachable(dest),
+          path: vec![sid, s1],
+        })
+        .await;
+      assert_eq!(r, ServerReply::Outgoing(Vec::new()));
+    })
+  }
+
+  #[test]
+  fn local_failure() {
+    async_std::task::block_on(async {
+      let sid = ServerId::default();
+      let server: Server<TestChecker> = Server::new(TestChecker::default(), sid);
+      let src = server
+        .register_local_client("127.0.0.1".parse().unwrap(), "user".to_string())
+        .await
+        .unwrap();
+      let dest = ClientId::default();
+      let r = server
+        .handle_server_message(ServerMessage::Failure {
+          src,
+          srcsrv: sid,
+          error: DelayedError::Unreachable(dest),
+          path: vec![ServerId::from(1)],
+        })
+        .await;
+      assert_eq!(r, ServerReply::Outgoing(Vec::new()));
+      let r = server.client_poll(src).await;
+      assert_eq!(
+        r,
+        ClientPollReply::DelayedError(DelayedError::Unreachable(dest))
+      );
+    })
+  }
+
+  #[test]
+  fn split_by_nexthop() {
+    async_std::task::block_on(async {
+      let sid = ServerId::default();
+      let server: Server<TestChecker> = Server::new(TestChecker::default(), sid);
+      let (s1, s2, s3) = (ServerId::from(1), ServerId::from(2), ServerId::from(3));
+      let src = server
+        .register_local_client("127.0.0.1".parse().unwrap(), "src".to_string())
+        .await
+        .unwrap();
+      let local = server
+        .register_local_client("127.0.0.1".parse().unwrap(), "local".to_string())
+        .await
+        .unwrap();
+      let [a, b, c, d, unknown] = [(); 5].map(|_| ClientId::default());
+      server
+        .handle_server_message(announce(vec![s1], &[a, b]))
+        .await;
+      server
+        .handle_server_message(announce(vec![s2, s1], &[c]))
+        .await;
+      server.handle_server_message(announce(vec![s3], &[d])).await;
+      let message = |dsts: Vec<(ClientId, ServerId)>| {
+        ServerMessage::Message(FullyQualifiedMessage {
+          src,
+          srcsrv: sid,
+          dsts,
+          content: "split".to_string(),
+        })
+      };
+
+      let r = server
+        .handle_client_message(
+          src,
+          ClientMessage::MText {
+            dest: vec![a, local, c, a, d, b, unknown],
+            content: "split".to_string(),
+          },
+        )
+        .await;
+      let expected = vec![
+        ClientReply::Transfer(s1, message(vec![(a, s1), (c, s2), (b, s1)])),
+        ClientReply::Delivered,
+        ClientReply::Grouped(s1),
+        ClientReply::Grouped(s1),
+        ClientReply::Transfer(s3, message(vec![(d, s3)])),
+        ClientReply::Grouped(s1),
+        ClientReply::Delayed,
+      ];
+      assert_eq!(r, expected);
+
+      // messages from other servers are split the same way
+      let r = server
+        .handle_server_message(ServerMessage::Message(FullyQualifiedMessage {
+          src,
+          srcsrv: s3,
+          dsts: vec![(a, s1), (d, s3), (c, s2), (a, s1), (local, sid)],
+          content: "split".to_string(),
+        }))
+        .await;
+      let relay = |dsts: Vec<(ClientId, ServerId)>| ServerMessage::Relay {
+        message: FullyQualifiedMessage {
+          src,
+          srcsrv: s3,
+          dsts,
+          content: "split".to_string(),
+        },
+        path: vec![sid],
+      };
+      let expected = ServerReply::Relay(vec![
+        Outgoing {
+          nexthop: s1,
+          message: relay(vec![(a, s1), (c, s2)]),
+        },
+        Outgoing {
+          nexthop: s3,
+          message: relay(vec![(d, s3)]),
+        },
+      ]);
+      assert_eq!(r, expected);
+      assert!(matches!(
+        server.client_poll(local).await,
+        ClientPollReply::Message { .. }
+      ));
+      assert!(matches!(
+        server.client_poll(local).await,
+        ClientPollReply::Message { .. }
+      ));
+    })
+  }
+
+  #[test]
+  fn delayed_expiry() {
+    async_std::task::block_on(async {
+      let sid = ServerId::default();
+      let ttl = Duration::from_secs(60);
+      let clock = TestClock::new();
+      let server: Server<TestChecker> =
+        Server::with_clock(TestChecker::default(), sid, Arc::new(clock.clone()))
+          .with_delayed_ttl(ttl)
+          .with_route_ttl(ttl * 3);
+      let src = server
+        .register_local_client("127.0.0.1".parse().unwrap(), "src".to_string())
+        .await
+        .unwrap();
+      let (known, unknown) = (ClientId::default(), ClientId::default());
+      for dest in [known, unknown] {
+        let r = server
+          .handle_client_message(
+            src,
+            ClientMessage::Text {
+              dest,
+              content: "hello".to_string(),
+            },
+          )
+          .await;
+        assert_eq!(r, [ClientReply::Delayed]);
+      }
+
+      clock.advance(ttl / 2);
+      server.tick().await;
+      assert_eq!(server.client_poll(src).await, ClientPollReply::Nothing);
+      // the first recipient shows up in time
+      let s1 = ServerId::from(1);
+      let r = server
+        .handle_server_message(announce(vec![s1], &[known]))
+        .await;
+      assert!(matches!(r, ServerReply::Outgoing(o) if o.len() == 1));
+
+      clock.advance(ttl);
+      server.tick().await;
+      assert_eq!(
+        server.client_poll(src).await,
+        ClientPollReply::DelayedError(DelayedError::UnknownRecipient(unknown))
+      );
+      assert_eq!(server.client_poll(src).await, ClientPollReply::Nothing);
+      // the message is gone
+      let r = server
+        .handle_server_message(announce(vec![s1], &[unknown]))
+        .await;
+      assert_eq!(r, ServerReply::Outgoing(Vec::new()));
+    })
+  }
+
+  #[test]
+  fn topology_dot() {
+    async_std::task::block_on(async {
+      let sid = ServerId::default();
+      let server: Server<TestChecker> = Server::new(TestChecker::default(), sid);
+      let local = server
+        .register_local_client("127.0.0.1".parse().unwrap(), "local".to_string())
+        .await
+        .unwrap();
+      let (s1, s2) = (ServerId::from(1), ServerId::from(2));
+      let c2 = ClientId::default();
+      server
+        .handle_server_message(announce(vec![s2, s1], &[c2]))
+        .await;
+      let dot = server.topology_dot().await.unwrap();
+      assert!(dot.contains(&format!("\"{}\" [label=\"local\", shape=box];", local.0)));
+      assert!(dot.contains(&format!("\"{}\" [label=\"remote\", shape=box];", c2.0)));
+      assert!(dot.contains(&format!("\"{}\" -- \"{}\" [style=dotted];", c2.0, s2.0)));
+      assert!(dot.contains("\\nvia 01000000"));
+    })
+  }
+
+  #[test]
+  fn concurrent_clients() {
+    async_std::task::block_on(async {
+      let server = Arc::new(Server::new(TestChecker::default(), ServerId::default()));
+      let mut clients = Vec::new();
+      for i in 0..64 {
+        let name = format!("client{}", i);
+        let id = server
+          .register_local_client("127.0.0.1".parse().unwrap(), name)
+          .await
+          .unwrap();
+        clients.push(id);
+      }
+      // each client sends a message to the next one, at the same time
+      let tasks: Vec<_> = (0..clients.len())
+        .map(|i| {
+          let server = server.clone();
+          let (src, dest) = (clients[i], clients[(i + 1) % clients.len()]);
+          async_std::task::spawn(async move {
+            let msg = ClientMessage::Text {
+              dest,
+              content: src.to_string(),
+            };
+            assert_eq!(
+              server.handle_client_message(src, msg).await,
+              vec![ClientReply::Delivered]
+            );
+          })
+        })
+        .collect();
+      for task in tasks {
+        task.await;
+      }
+      assert_eq!(server.list_users().await.len(), clients.len());
+      for (i, client) in clients.iter().enumerate() {
+        let src = clients[(i + clients.len() - 1) % clients.len()];
+        assert_eq!(
+          server.client_poll(*client).await,
+          ClientPollReply::Message {
+            src,
+            content: src.to_string()
+          }
+        );
+      }
+    })
+  }
+}
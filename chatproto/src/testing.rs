@@ -660,6 +660,52 @@ async fn routing_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
   Ok(())
 }
 
+async fn weighted_routing_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid);
+
+  /* map, with the cost of the links:
+
+       us -1- s1 -1- s2
+        |             |
+       10             1
+        |             |
+       s5 -10- s4 -1- s3
+  */
+  let s1 = ServerId::from(1);
+  let s2 = ServerId::from(2);
+  let s3 = ServerId::from(3);
+  let s4 = ServerId::from(4);
+  let s5 = ServerId::from(5);
+  let expected_empty_out = ServerReply::Outgoing(Vec::new());
+  let r = server
+    .handle_server_message(ServerMessage::WeightedAnnounce {
+      route: vec![s4, s3, s2, s1],
+      clients: HashMap::new(),
+      metrics: vec![1, 1, 1, 1],
+    })
+    .await;
+  if r != expected_empty_out {
+    anyhow::bail!("msg1: Expected {:?}\n,    got {:?}", expected_empty_out, r);
+  }
+  let r = server
+    .handle_server_message(ServerMessage::WeightedAnnounce {
+      route: vec![s2, s3, s4, s5],
+      clients: HashMap::new(),
+      metrics: vec![1, 1, 10, 10],
+    })
+    .await;
+  if r != expected_empty_out {
+    anyhow::bail!("msg2: Expected {:?}\n,    got {:?}", expected_empty_out, r);
+  }
+  // the shortest route is the most expensive one
+  test_route(&server, s4, vec![sid, s1, s2, s3, s4])
+    .await
+    .context("r1")?;
+  test_route(&server, s5, vec![sid, s5]).await.context("r2")?;
+  Ok(())
+}
+
 async fn routing_test2<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
   let sid = ServerId::default();
   let server: M = MessageServer::new(TestChecker::default(), sid);
@@ -788,3 +834,14 @@ pub(crate) fn test_message_server<M: MessageServer<TestChecker>>() {
     }
   });
 }
+
+/// `WeightedAnnounce` is an extension of the protocol, so it is not part of `all_tests`, the
+/// implementations that support it run this test too
+pub(crate) fn test_weighted_routing<M: MessageServer<TestChecker>>() {
+  let _ = pretty_env_logger::try_init();
+  async_std::task::block_on(async {
+    if let Err(rr) = weighted_routing_test::<M>().await {
+      panic!("weighted routing, error={:?}", rr);
+    }
+  });
+}
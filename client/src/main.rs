@@ -1,11 +1,12 @@
 use async_std::channel::{Receiver, Sender};
 use async_std::net::UdpSocket;
 use async_std::sync::RwLock;
+use chatproto::auth::{Handshake, Session};
 use chatproto::client::Client;
 use chatproto::messages::{
   ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
 };
-use chatproto::netproto::{decode, encode};
+use chatproto::netproto::{decode, encode, DecodeError};
 use crossterm::event::KeyEventKind;
 use crossterm::{
   event::{DisableMouseCapture, EnableMouseCapture, KeyCode},
@@ -48,30 +49,57 @@ struct Opt {
 
 struct Network {
   socket: UdpSocket,
+  /// set once authenticated, all messages are then authenticated with a MAC
+  session: Option<Session>,
+  /// sequence number of the last query, the reply MAC depends on it
+  seqid: u128,
 }
 
 impl Network {
   async fn new(target: SocketAddr) -> anyhow::Result<Self> {
     let socket = UdpSocket::bind("127.0.0.1:0").await?;
     socket.connect(target).await?;
-    Ok(Self { socket })
+    Ok(Self {
+      socket,
+      session: None,
+      seqid: 0,
+    })
   }
 
-  async fn send(&self, sq: &Sequence<ClientQuery>) -> anyhow::Result<()> {
+  async fn send(&mut self, sq: &Sequence<ClientQuery>) -> anyhow::Result<()> {
     let mut wr = Cursor::new(Vec::new());
     encode::sequence(&mut wr, sq, encode::client_query)?;
-    self.socket.send(&wr.into_inner()).await?;
+    let mut packet = wr.into_inner();
+    if let Some(session) = &self.session {
+      session.seal_query(&mut packet);
+    }
+    self.seqid = sq.seqid;
+    self.socket.send(&packet).await?;
     Ok(())
   }
 
   async fn get<X, F>(&self, f: F) -> anyhow::Result<X>
   where
-    F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+    F: FnOnce(&mut Cursor<Vec<u8>>) -> Result<X, DecodeError>,
   {
     let mut buf = vec![0u8; 8192];
     let n = self.socket.recv(&mut buf).await?;
-    let mut cursor = Cursor::new(buf[..n].to_vec());
-    f(&mut cursor)
+    let payload = match &self.session {
+      None => &buf[..n],
+      Some(session) => session.open_reply(self.seqid, &buf[..n])?,
+    };
+    let mut cursor = Cursor::new(payload.to_vec());
+    match f(&mut cursor) {
+      Ok(x) => Ok(x),
+      // the server answers the queries it could not handle with an error
+      Err(rr) => match decode::client_replies(&mut Cursor::new(payload)) {
+        Ok(repl) => match repl.as_slice() {
+          [ClientReply::Error(err)] => Err(err.clone().into()),
+          _ => Err(rr.into()),
+        },
+        Err(_) => Err(rr.into()),
+      },
+    }
   }
 }
 
@@ -305,7 +333,7 @@ fn ui(f: &mut Frame, input: &inputbox::IBox, users: &Users, errors: &[String]) {
 
 async fn handle_network(
   client: Client,
-  network: Network,
+  mut network: Network,
   event_tx: Sender<UIEvent>,
   rx: Receiver<Command>,
 ) -> anyhow::Result<()> {
@@ -411,7 +439,8 @@ async fn handle_network(
               .write()
               .await
               .push(format!("message to {}: {}", target, rr)),
-            ClientReply::Transfer(_, _) => todo!(),
+            // the server forwarded the message to another server
+            ClientReply::Transfer(_, _) | ClientReply::Grouped(_) => (),
           }
         }
       }
@@ -429,7 +458,7 @@ async fn main_task() -> anyhow::Result<()> {
   pretty_env_logger::init();
 
   let opt = Opt::from_args();
-  let network = Network::new((opt.host, opt.port).into()).await?;
+  let mut network = Network::new((opt.host, opt.port).into()).await?;
   let tempid = ClientId::default();
 
   let sq = Sequence {
@@ -439,9 +468,22 @@ async fn main_task() -> anyhow::Result<()> {
   };
 
   network.send(&sq).await?;
-  let id = network.get(decode::clientid).await?;
+  let registered = network.get(decode::registered).await?;
+  let id = registered.id;
   log::info!("registered as {}", id);
-  let client = Client::new(id);
+  let mut client = Client::new(id);
+
+  let mut handshake = Handshake::new(id, registered.secret);
+  network
+    .send(&client.sequence(ClientQuery::Auth(handshake.hello())))
+    .await?;
+  let challenge = network.get(decode::auth).await?;
+  let response = handshake.respond(&challenge)?;
+  network
+    .send(&client.sequence(ClientQuery::Auth(response)))
+    .await?;
+  network.session = Some(handshake.finish(&network.get(decode::auth).await?)?);
+  log::info!("authenticated");
 
   let (tx, rx) = async_std::channel::bounded::<Command>(16);
   let (event_tx, event_rx) = async_std::channel::bounded::<UIEvent>(32);
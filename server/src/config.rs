@@ -0,0 +1,104 @@
+/* Peer configuration file.
+
+  Each line is empty, a comment starting with `#`, or one of:
+   * `peer <server id>@<address>`: a server to connect to, like `--peer`,
+   * `key <server id>=<hex key>`: the pre-shared key of a server, like `--peer-key`.
+*/
+use chatproto::peers::{PeerAddr, PeerKey};
+use std::path::Path;
+
+#[derive(Default)]
+pub struct PeerConfig {
+  pub peers: Vec<PeerAddr>,
+  pub keys: Vec<PeerKey>,
+}
+
+impl PeerConfig {
+  pub fn parse(content: &str) -> anyhow::Result<Self> {
+    let mut config = PeerConfig::default();
+    for (n, line) in content.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let parsed = match line.split_once(char::is_whitespace) {
+        Some(("peer", value)) => value.trim().parse().map(|p| config.peers.push(p)),
+        Some(("key", value)) => value.trim().parse().map(|k| config.keys.push(k)),
+        _ => Err("expected peer or key".to_string()),
+      };
+      if let Err(rr) = parsed {
+        anyhow::bail!("line {}: {}", n + 1, rr);
+      }
+    }
+    Ok(config)
+  }
+
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    let content = std::fs::read_to_string(path)?;
+    Self::parse(&content).map_err(|rr| anyhow::anyhow!("{}: {}", path.display(), rr))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+  #[test]
+  fn valid() {
+    let content = format!(
+      "# peers of the test server\n\npeer {id}@127.0.0.1:4667\n  key {id}=00ff10  \n\t\n  # done\n",
+      id = ID
+    );
+    let config = PeerConfig::parse(&content).unwrap();
+    assert_eq!(
+      config.peers,
+      vec![format!("{}@127.0.0.1:4667", ID).parse().unwrap()]
+    );
+    assert_eq!(config.keys.len(), 1);
+    assert_eq!(config.keys[0].server, ID.parse().unwrap());
+    assert_eq!(config.keys[0].key, vec![0, 255, 16]);
+
+    let empty = PeerConfig::parse("\n# nothing\n\n").unwrap();
+    assert!(empty.peers.is_empty() && empty.keys.is_empty());
+  }
+
+  fn error(content: &str) -> String {
+    match PeerConfig::parse(content) {
+      Ok(_) => panic!("{:?} was accepted", content),
+      Err(rr) => rr.to_string(),
+    }
+  }
+
+  #[test]
+  fn malformed() {
+    // the line numbers count the comments and blank lines
+    let rr = error(&format!(
+      "# comment\n\npeer 67e55044@127.0.0.1:4667\nkey {}=00",
+      ID
+    ));
+    assert!(rr.starts_with("line 3:"), "{}", rr);
+    let rr = error(&format!(
+      "peer {}@127.0.0.1:4667\npeer {}@localhost",
+      ID, ID
+    ));
+    assert!(rr.starts_with("line 2:"), "{}", rr);
+    let rr = error(&format!("key {}=0", ID));
+    assert!(rr.starts_with("line 1:"), "{}", rr);
+    let rr = error(&format!("server {}@127.0.0.1:4667", ID));
+    assert!(rr.starts_with("line 1:"), "{}", rr);
+    let rr = error("peer");
+    assert!(rr.starts_with("line 1:"), "{}", rr);
+  }
+
+  #[test]
+  fn load() {
+    let path = std::env::temp_dir().join(format!("peers-{}.conf", std::process::id()));
+    std::fs::write(&path, format!("peer {}@127.0.0.1:4667\n", ID)).unwrap();
+    let config = PeerConfig::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(config.unwrap().peers.len(), 1);
+    assert!(PeerConfig::load(&path).is_err());
+  }
+}
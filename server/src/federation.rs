@@ -0,0 +1,495 @@
+/* Links with the other servers.
+
+  Messages on the server port are `PeerMessage`s, that are either part of the handshake, or
+  authenticated server messages (see `chatproto::peers`). Once a peer completes the handshake, the
+  link it used is recorded in the peer table, and used to send it the messages it is the next hop
+  for.
+*/
+use async_std::channel::{bounded, Sender};
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
+use async_std::task;
+use chatproto::core::DynMessageServer;
+use chatproto::messages::{PeerMessage, ServerId, ServerMessage, ServerReply};
+use chatproto::netproto::frame::{FrameError, FrameReader, FrameWriter};
+use chatproto::netproto::{decode, encode};
+use chatproto::peers::{PeerAddr, PeerEvent};
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{datagram_limits, State};
+
+/// size of the queue of messages waiting to be written on a TCP connection
+const LINK_QUEUE: usize = 64;
+
+/// delay between two handshake attempts with a configured peer
+const RECONNECT: Duration = Duration::from_secs(5);
+
+/// how to reach a peer server
+#[derive(Clone)]
+pub enum Link {
+  /// datagrams, sent from our server socket
+  Udp(Arc<UdpSocket>, SocketAddr),
+  /// a TCP connection, the frames are written by the connection writer task
+  Tcp(Sender<PeerMessage>, SocketAddr),
+}
+
+impl Link {
+  pub fn addr(&self) -> SocketAddr {
+    match self {
+      Link::Udp(_, addr) | Link::Tcp(_, addr) => *addr,
+    }
+  }
+
+  fn is_open(&self) -> bool {
+    match self {
+      Link::Udp(_, _) => true,
+      Link::Tcp(tx, _) => !tx.is_closed(),
+    }
+  }
+
+  fn same(&self, other: &Link) -> bool {
+    match (self, other) {
+      (Link::Udp(_, a), Link::Udp(_, b)) | (Link::Tcp(_, a), Link::Tcp(_, b)) => a == b,
+      _ => false,
+    }
+  }
+
+  pub async fn send(&self, msg: PeerMessage) -> anyhow::Result<()> {
+    match self {
+      Link::Udp(socket, addr) => {
+        let mut ocurs = Cursor::new(Vec::new());
+        encode::peer(&mut ocurs, &msg)?;
+        socket.send_to(&ocurs.into_inner(), addr).await?;
+      }
+      Link::Tcp(tx, _) => tx.send(msg).await?,
+    }
+    Ok(())
+  }
+}
+
+/// sends a server message to an authenticated peer
+pub async fn send_to_peer<S>(
+  state: &State<S>,
+  dest: ServerId,
+  msg: ServerMessage,
+) -> anyhow::Result<()> {
+  let link = state
+    .links
+    .lock()
+    .unwrap()
+    .get(&dest)
+    .cloned()
+    .ok_or_else(|| anyhow::anyhow!("no link to {}", dest))?;
+  // the peers accept messages out of order, within their replay window
+  let sealed = state.peers.lock().await.seal(dest, msg)?;
+  link.send(sealed).await
+}
+
+async fn handle_server_message<S: DynMessageServer>(
+  src: ServerId,
+  state: &State<S>,
+  msg: ServerMessage,
+) {
+  let forward = match &msg {
+    ServerMessage::Announce { .. }
+    | ServerMessage::WeightedAnnounce { .. }
+    | ServerMessage::Withdraw { .. }
+    | ServerMessage::Unlink { .. } => Some(msg.clone()),
+    ServerMessage::Message(_) | ServerMessage::Failure { .. } | ServerMessage::Relay { .. } => None,
+  };
+  let reply = state.srv.handle_server_message(msg).await;
+  if let (ServerReply::Outgoing(_), Some(msg)) = (&reply, forward) {
+    propagate(src, state, msg).await;
+  }
+  match reply {
+    ServerReply::Outgoing(outgoing) => {
+      for o in outgoing {
+        let nexthop = o.nexthop;
+        if let Err(rr) = send_to_peer(state, nexthop, ServerMessage::Message(o.message)).await {
+          log::error!("Could not forward message to {}: {}", nexthop, rr);
+        }
+      }
+    }
+    ServerReply::Relay(outgoing) => {
+      for o in outgoing {
+        let nexthop = o.nexthop;
+        if let Err(rr) = send_to_peer(state, nexthop, o.message).await {
+          log::error!("Could not forward message to {}: {}", nexthop, rr);
+        }
+      }
+    }
+    ServerReply::EmptyRoute => {
+      log::error!("Received an announce with an empty route from {}", src)
+    }
+    ServerReply::Error(rr) => {
+      log::error!("Error occured when handling message from {}: {}", src, rr)
+    }
+  }
+}
+
+/// Forwards a received announce or withdrawal to the other neighbours, see
+/// `chatproto::routing::Propagation`.
+async fn propagate<S>(src: ServerId, state: &State<S>, msg: ServerMessage) {
+  let peers = state.peers.lock().await;
+  let neighbours: Vec<_> = neighbours(state)
+    .into_iter()
+    .map(|dest| (dest, peers.metric(&dest)))
+    .collect();
+  drop(peers);
+  let forwarded =
+    state
+      .propagation
+      .lock()
+      .unwrap()
+      .propagate(src, msg, neighbours, state.clock.now());
+  for (dest, msg) in forwarded {
+    if let Err(rr) = send_to_peer(state, dest, msg).await {
+      log::error!("Could not forward route to {}: {}", dest, rr);
+    }
+  }
+}
+
+/// appends the cost of the link to `dest` to an announce
+async fn with_metric<S>(state: &State<S>, dest: ServerId, mut msg: ServerMessage) -> ServerMessage {
+  if let ServerMessage::WeightedAnnounce { metrics, .. } = &mut msg {
+    metrics.push(state.peers.lock().await.metric(&dest));
+  }
+  msg
+}
+
+/// the authenticated peers
+fn neighbours<S>(state: &State<S>) -> Vec<ServerId> {
+  state.links.lock().unwrap().keys().copied().collect()
+}
+
+/// Periodically announces the local clients to every authenticated peer, and measures the
+/// round-trip time of the links for the next announces.
+pub async fn announce_thread<S: DynMessageServer>(every: Duration, state: Arc<State<S>>) {
+  let id = state.peers.lock().await.id();
+  loop {
+    task::sleep(every).await;
+    let mut clients = state.srv.list_users().await;
+    {
+      // the users known through announces are forwarded by `propagate`
+      let auth = state.auth.lock().unwrap();
+      clients.retain(|c, _| auth.is_registered(c));
+    }
+    for dest in neighbours(&state) {
+      let msg = ServerMessage::WeightedAnnounce {
+        route: vec![id],
+        clients: clients.clone(),
+        metrics: Vec::new(),
+      };
+      let msg = with_metric(&state, dest, msg).await;
+      if let Err(rr) = send_to_peer(&state, dest, msg).await {
+        log::error!("Could not announce to {}: {}", dest, rr);
+      }
+      if let Err(rr) = ping(&state, dest).await {
+        log::error!("Could not ping {}: {}", dest, rr);
+      }
+    }
+  }
+}
+
+async fn ping<S>(state: &State<S>, dest: ServerId) -> anyhow::Result<()> {
+  let link = state
+    .links
+    .lock()
+    .unwrap()
+    .get(&dest)
+    .cloned()
+    .ok_or_else(|| anyhow::anyhow!("no link to {}", dest))?;
+  let ping = state.peers.lock().await.ping(dest)?;
+  link.send(ping).await
+}
+
+/// Handles a message received on the server port, and returns the reply, if any.
+/// Server messages are only accepted from authenticated peers, see the `peers` module.
+async fn handle_peer_message<S: DynMessageServer>(
+  link: &Link,
+  state: &State<S>,
+  msg: PeerMessage,
+) -> Option<PeerMessage> {
+  let event = state.peers.lock().await.handle(link.addr(), msg);
+  let (src, reply) = match event {
+    Err(rr) => {
+      log::error!("Rejected server message from {}: {}", link.addr(), rr);
+      return None;
+    }
+    Ok(PeerEvent::Reply(reply)) => return Some(reply),
+    Ok(PeerEvent::Accepted(src, reply)) => {
+      log::info!("Accepted {} from {}", src, link.addr());
+      (src, Some(reply))
+    }
+    Ok(PeerEvent::Connected(src)) => {
+      log::info!("Connected to {} at {}", src, link.addr());
+      (src, None)
+    }
+    Ok(PeerEvent::Measured(src, rtt)) => {
+      log::debug!("Round-trip time to {} is {:?}", src, rtt);
+      return None;
+    }
+    Ok(PeerEvent::Message(src, msg)) => {
+      handle_server_message(src, state, msg).await;
+      return None;
+    }
+  };
+  // the peer proved it knows the key on this link, authenticated messages can be replayed from
+  // anywhere, so they do not move it
+  state.links.lock().unwrap().insert(src, link.clone());
+  reply
+}
+
+pub async fn server_thread<S: DynMessageServer>(
+  socket: Arc<UdpSocket>,
+  state: &State<S>,
+) -> std::io::Result<()> {
+  log::info!("Listening for servers on {}", socket.local_addr()?);
+  let mut buf = vec![0u8; 8192];
+  loop {
+    let (n, peer) = socket.recv_from(&mut buf).await?;
+    match decode::strict::<PeerMessage>(&buf[..n], datagram_limits()) {
+      Err(rr) => state.stats.decode_error("server message", peer, &rr),
+      Ok(msg) => {
+        let link = Link::Udp(socket.clone(), peer);
+        if let Some(reply) = handle_peer_message(&link, state, msg).await {
+          if let Err(rr) = link.send(reply).await {
+            log::error!("Error when sending message to {}: {}", peer, rr);
+          }
+        }
+      }
+    }
+  }
+}
+
+async fn server_connection<S: DynMessageServer>(stream: TcpStream, link: Link, state: &State<S>) {
+  let peer = link.addr();
+  let mut rd = FrameReader::new(stream);
+  loop {
+    match rd.recv::<PeerMessage>().await {
+      Ok(None) => break,
+      Ok(Some(msg)) => {
+        if let Some(reply) = handle_peer_message(&link, state, msg).await {
+          if let Err(rr) = link.send(reply).await {
+            log::error!("Error when sending message to {}: {}", peer, rr);
+            break;
+          }
+        }
+      }
+      // the frames are still aligned, skip the message
+      Err(FrameError::Decode(rr)) => state.stats.decode_error("server message", peer, &rr),
+      Err(rr) => {
+        state.stats.frame_error("server message", peer, &rr);
+        break;
+      }
+    }
+  }
+  let mut lost = Vec::new();
+  state.links.lock().unwrap().retain(|server, l| {
+    if l.same(&link) {
+      lost.push(*server);
+    }
+    !l.same(&link)
+  });
+  // only the links are lost, the peers might still be reachable through other servers
+  let id = state.peers.lock().await.id();
+  for server in lost {
+    let unlink = ServerMessage::Unlink { a: id, b: server };
+    handle_server_message(server, state, unlink).await;
+  }
+  if let Link::Tcp(tx, _) = link {
+    tx.close();
+  }
+  log::debug!("server connection with {} closed", peer);
+}
+
+/// starts the tasks handling a TCP connection with another server, and returns its link
+fn open_connection<S>(stream: TcpStream, peer: SocketAddr, state: Arc<State<S>>) -> Link
+where
+  S: DynMessageServer + 'static,
+{
+  let (tx, rx) = bounded::<PeerMessage>(LINK_QUEUE);
+  let mut wr = FrameWriter::new(stream.clone());
+  task::spawn(async move {
+    while let Ok(msg) = rx.recv().await {
+      if let Err(rr) = wr.send(&msg).await {
+        log::error!("Error when sending message to {}: {}", peer, rr);
+        break;
+      }
+    }
+  });
+  let link = Link::Tcp(tx, peer);
+  let rlink = link.clone();
+  task::spawn(async move { server_connection(stream, rlink, &state).await });
+  link
+}
+
+pub async fn server_tcp_thread<S>(
+  listen: IpAddr,
+  port: u16,
+  state: Arc<State<S>>,
+) -> std::io::Result<()>
+where
+  S: DynMessageServer + 'static,
+{
+  let listener = TcpListener::bind((listen, port)).await?;
+  log::info!("Listening for servers on tcp/{}", listener.local_addr()?);
+  loop {
+    let (stream, peer) = listener.accept().await?;
+    open_connection(stream, peer, state.clone());
+  }
+}
+
+/// Starts the handshake with the configured peers, and retries until they are connected.
+/// The handshake is sent from the UDP server socket if set, or with a new TCP connection.
+pub async fn connector_thread<S>(
+  peers: Vec<PeerAddr>,
+  socket: Option<Arc<UdpSocket>>,
+  state: Arc<State<S>>,
+) where
+  S: DynMessageServer + 'static,
+{
+  let mut pending: Vec<Option<Link>> = vec![None; peers.len()];
+  loop {
+    for (p, link) in peers.iter().zip(pending.iter_mut()) {
+      if state.peers.lock().await.is_connected(&p.server) {
+        continue;
+      }
+      let current = match (link.as_ref().filter(|l| l.is_open()), &socket) {
+        (Some(l), _) => l.clone(),
+        (None, Some(socket)) => Link::Udp(socket.clone(), p.addr),
+        (None, None) => match TcpStream::connect(p.addr).await {
+          Ok(stream) => open_connection(stream, p.addr, state.clone()),
+          Err(rr) => {
+            log::warn!("Could not connect to {} at {}: {}", p.server, p.addr, rr);
+            continue;
+          }
+        },
+      };
+      let hello = state.peers.lock().await.connect(p.server);
+      let sent = match hello {
+        Ok(hello) => current.send(hello).await,
+        Err(rr) => Err(rr.into()),
+      };
+      if let Err(rr) = sent {
+        log::warn!("Could not start handshake with {}: {}", p.server, rr);
+      }
+      *link = Some(current);
+    }
+    task::sleep(RECONNECT).await;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use chatproto::core::DefaultChecker;
+  use chatproto::messages::{ClientMessage, ClientPollReply, ClientReply};
+  use chatproto::peers::PeerKey;
+  use chatproto::solutions::{Settings, IMPLEMENTATIONS};
+  use std::future::Future;
+  use std::net::Ipv4Addr;
+
+  type TestState = Arc<State<Box<dyn DynMessageServer>>>;
+
+  /// a server listening on a loopback port, that announces its clients every 50ms
+  async fn start(id: ServerId, peer: ServerId) -> (TestState, Arc<UdpSocket>) {
+    let srv = (IMPLEMENTATIONS[0].new)(DefaultChecker::default(), id, &Settings::default());
+    let key = PeerKey {
+      server: peer,
+      key: b"loopback".to_vec(),
+    };
+    let state = Arc::new(State::new(srv, id, vec![key], &Settings::default()));
+    let socket = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap());
+    let (ssocket, sstate) = (socket.clone(), state.clone());
+    task::spawn(async move { server_thread(ssocket, &sstate).await });
+    task::spawn(announce_thread(Duration::from_millis(50), state.clone()));
+    (state, socket)
+  }
+
+  async fn eventually<X, F, R>(mut f: F) -> X
+  where
+    F: FnMut() -> R,
+    R: Future<Output = Option<X>>,
+  {
+    for _ in 0..200 {
+      if let Some(x) = f().await {
+        return x;
+      }
+      task::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out");
+  }
+
+  #[async_std::test]
+  async fn loopback() {
+    let (a, b) = (ServerId::default(), ServerId::default());
+    let (sa, socket_a) = start(a, b).await;
+    let (sb, socket_b) = start(b, a).await;
+    let (addr_a, addr_b) = (
+      socket_a.local_addr().unwrap(),
+      socket_b.local_addr().unwrap(),
+    );
+    let peer = PeerAddr {
+      server: b,
+      addr: addr_b,
+    };
+    task::spawn(connector_thread(vec![peer], Some(socket_a), sa.clone()));
+
+    let ip = IpAddr::from(Ipv4Addr::LOCALHOST);
+    let alice = sa
+      .srv
+      .register_local_client(ip, "alice".into())
+      .await
+      .unwrap();
+    sa.auth.lock().unwrap().register(alice);
+    let bob = sb
+      .srv
+      .register_local_client(ip, "bob".into())
+      .await
+      .unwrap();
+    sb.auth.lock().unwrap().register(bob);
+
+    let (sa, sb) = (&sa, &sb);
+    eventually(|| async move { sb.srv.list_users().await.remove(&alice) }).await;
+    let msg = ClientMessage::Text {
+      dest: alice,
+      content: "hello".into(),
+    };
+    for r in sb.srv.handle_client_message(bob, msg).await {
+      match r {
+        ClientReply::Transfer(dest, m) => send_to_peer(sb, dest, m).await.unwrap(),
+        r => panic!("expected a transfer, got {:?}", r),
+      }
+    }
+    let reply = eventually(|| async move {
+      match sa.srv.client_poll(alice).await {
+        ClientPollReply::Nothing => None,
+        r => Some(r),
+      }
+    })
+    .await;
+    assert_eq!(
+      reply,
+      ClientPollReply::Message {
+        src: bob,
+        content: "hello".into()
+      }
+    );
+
+    // a valid message sent from another address does not move the link
+    let withdraw = ServerMessage::Withdraw {
+      route: vec![b],
+      clients: Vec::new(),
+    };
+    let sealed = sb.peers.lock().await.seal(a, withdraw).unwrap();
+    let attacker = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap());
+    Link::Udp(attacker, addr_a).send(sealed).await.unwrap();
+    for _ in 0..20 {
+      assert_eq!(sa.links.lock().unwrap()[&b].addr(), addr_b);
+      task::sleep(Duration::from_millis(5)).await;
+    }
+  }
+}
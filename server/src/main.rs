@@ -1,15 +1,34 @@
-use async_std::net::UdpSocket;
-use async_std::sync::RwLock;
+use async_std::channel::bounded;
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
 use async_std::task;
-use chatproto::core::{DefaultChecker, MessageServer, SpamChecker};
-use chatproto::messages::ServerReply;
-use chatproto::messages::{ClientError, ClientQuery, Sequence, ServerId};
-use chatproto::netproto::{decode, encode};
+use chatproto::auth::Authenticator;
+use chatproto::core::{Clock, DefaultChecker, DynMessageServer, SpamChecker};
+use chatproto::messages::{
+  ClientError, ClientId, ClientQuery, ClientReply, Registered, Sequence, ServerId,
+};
+use chatproto::middleware::{Denylist, Latency, LayerExt, Tracing};
+use chatproto::netproto::decode::DecodeLimits;
+use chatproto::netproto::frame::{write_frame, FrameError, FrameReader};
+use chatproto::netproto::{decode, encode, DecodeError};
+use chatproto::peers::{PeerAddr, PeerKey, Peers};
+use chatproto::routing::Propagation;
+use chatproto::solutions::{Implementation, Settings, IMPLEMENTATIONS};
+use federation::{send_to_peer, Link};
+use std::collections::HashMap;
 use std::io::Cursor;
-use std::net::IpAddr;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use structopt::StructOpt;
 
+mod config;
+mod federation;
+
+/// number of client datagrams handled at the same time
+const CLIENT_WORKERS: usize = 64;
+
 #[derive(StructOpt)]
 struct Opt {
   #[structopt(long, default_value = "4666")]
@@ -27,72 +46,277 @@ struct Opt {
   #[structopt(long, default_value = "0.0.0.0")]
   /// address to listen for servers on
   slisten: IpAddr,
+
+  #[structopt(long, default_value = "udp")]
+  /// transport to listen on (udp, tcp or both), TCP uses the same ports as UDP
+  transport: Transport,
+
+  #[structopt(long)]
+  /// server id, random if not set
+  id: Option<ServerId>,
+
+  #[structopt(long = "peer-key", number_of_values = 1)]
+  /// pre-shared key for a peer server, as <server id>=<hex key>, can be repeated
+  peer_keys: Vec<PeerKey>,
+
+  #[structopt(long = "peer", number_of_values = 1)]
+  /// server port of a peer server to connect to, as <server id>@<address>, can be repeated
+  peers: Vec<PeerAddr>,
+
+  #[structopt(long, parse(from_os_str))]
+  /// file listing peers and their keys, see the `config` module
+  config: Option<PathBuf>,
+
+  #[structopt(long, default_value = "10")]
+  /// delay between two announces to the peers, in seconds
+  announce: u64,
+
+  #[structopt(long, default_value = "60")]
+  /// how long routes and remote users are kept when they are not announced again, in seconds
+  route_ttl: u64,
+
+  #[structopt(long, default_value = "600")]
+  /// how long messages for unknown users are kept, in seconds
+  delayed_ttl: u64,
+
+  #[structopt(long = "impl", default_value = "reference")]
+  /// implementation of the server, by group name, see --list-impls
+  implementation: String,
+
+  #[structopt(long)]
+  /// lists the implementations of the server and exits
+  list_impls: bool,
+
+  #[structopt(long = "layer", number_of_values = 1)]
+  /// middleware around the implementation (tracing, latency or denylist), can be repeated, the
+  /// last one is called first
+  layers: Vec<LayerKind>,
+
+  #[structopt(long = "deny", number_of_values = 1)]
+  /// client id rejected by the denylist layer, can be repeated
+  denied: Vec<ClientId>,
 }
 
-async fn server_thread<S: MessageServer<DefaultChecker>>(
-  listen: IpAddr,
-  port: u16,
-  srv: &RwLock<S>,
-) -> std::io::Result<()> {
-  let socket = UdpSocket::bind((listen, port)).await?;
-  log::info!("Listening for servers on {}", socket.local_addr()?);
-  let mut buf = vec![0u8; 8192];
-  loop {
-    let (n, peer) = socket.recv_from(&mut buf).await?;
-    let mut cursor = Cursor::new(buf[..n].to_vec());
-    match decode::server(&mut cursor) {
-      Err(rr) => log::error!("Could not decode server message from {}: {}", peer, rr),
-      Ok(msg) => match srv.write().await.handle_server_message(msg).await {
-        ServerReply::Outgoing(_) => todo!(),
-        ServerReply::EmptyRoute => todo!(),
-        ServerReply::Error(rr) => {
-          log::error!("Error occured when handling message from {}: {}", peer, rr)
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Transport {
+  Udp,
+  Tcp,
+  Both,
+}
+
+impl Transport {
+  fn udp(self) -> bool {
+    self != Transport::Tcp
+  }
+
+  fn tcp(self) -> bool {
+    self != Transport::Udp
+  }
+}
+
+impl FromStr for Transport {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "udp" => Ok(Transport::Udp),
+      "tcp" => Ok(Transport::Tcp),
+      "both" => Ok(Transport::Both),
+      _ => Err(format!(
+        "unknown transport {}, expected udp, tcp or both",
+        s
+      )),
+    }
+  }
+}
+
+/// a layer of `chatproto::middleware`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LayerKind {
+  Tracing,
+  Latency,
+  Denylist,
+}
+
+impl FromStr for LayerKind {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "tracing" => Ok(LayerKind::Tracing),
+      "latency" => Ok(LayerKind::Latency),
+      "denylist" => Ok(LayerKind::Denylist),
+      _ => Err(format!(
+        "unknown layer {}, expected tracing, latency or denylist",
+        s
+      )),
+    }
+  }
+}
+
+/// the layers around the server that can be used with admin commands
+#[derive(Default)]
+struct Middleware {
+  latency: Option<Latency>,
+  denylist: Option<Denylist>,
+}
+
+impl Middleware {
+  /// wraps the server in the layers, in order
+  fn wrap(
+    &mut self,
+    layers: &[LayerKind],
+    mut server: Box<dyn DynMessageServer>,
+  ) -> Box<dyn DynMessageServer> {
+    for layer in layers {
+      server = match layer {
+        LayerKind::Tracing => Box::new(server.layer(Tracing)),
+        LayerKind::Latency => {
+          let latency = self.latency.get_or_insert_with(Latency::default);
+          Box::new(server.layer(latency.clone()))
         }
-      },
+        LayerKind::Denylist => {
+          let denylist = self.denylist.get_or_insert_with(Denylist::default);
+          Box::new(server.layer(denylist.clone()))
+        }
+      };
     }
+    server
   }
 }
 
-async fn handle_client_query<S: MessageServer<DefaultChecker>>(
-  src_ip: IpAddr,
-  srv: &RwLock<S>,
+/// protocol statistics
+#[derive(Default)]
+struct Stats {
+  /// decoding errors, by kind
+  decode_errors: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl Stats {
+  fn frame_error(&self, what: &str, peer: SocketAddr, rr: &FrameError) {
+    match rr {
+      FrameError::Decode(rr) => self.decode_error(what, peer, rr),
+      rr => log::error!("Could not read {} from {}: {}", what, peer, rr),
+    }
+  }
+
+  fn decode_error(&self, what: &str, peer: SocketAddr, rr: &DecodeError) {
+    let count = {
+      let mut errors = self.decode_errors.lock().unwrap();
+      let count = errors.entry(rr.kind()).or_default();
+      *count += 1;
+      *count
+    };
+    log::error!(
+      "Could not decode {} from {}: {} ({} {} errors so far)",
+      what,
+      peer,
+      rr,
+      count,
+      rr.kind()
+    );
+  }
+}
+
+/// state shared by all the listeners
+struct State<S> {
+  /// the methods of the server take `&self`, so that queries can be handled concurrently
+  srv: S,
+  /// the clock of the server, also used for the handshakes and the routes
+  clock: Arc<dyn Clock>,
+  auth: Mutex<Authenticator>,
+  peers: async_std::sync::Mutex<Peers>,
+  /// how to reach the authenticated peers
+  links: Mutex<HashMap<ServerId, Link>>,
+  /// the announces forwarded to the peers
+  propagation: Mutex<Propagation>,
+  stats: Stats,
+  middleware: Middleware,
+}
+
+impl<S> State<S> {
+  fn new(srv: S, id: ServerId, peer_keys: Vec<PeerKey>, settings: &Settings) -> Self {
+    let clock = settings.clock.clone();
+    State {
+      srv,
+      auth: Mutex::new(Authenticator::with_clock(id, clock.clone())),
+      peers: async_std::sync::Mutex::new(Peers::with_clock(id, peer_keys, clock.clone())),
+      clock,
+      links: Mutex::new(HashMap::new()),
+      propagation: Mutex::new(Propagation::new(id, settings.route_ttl)),
+      stats: Stats::default(),
+      middleware: Middleware::default(),
+    }
+  }
+}
+
+// a message can't be larger than the receive buffer
+fn datagram_limits() -> DecodeLimits {
+  DecodeLimits {
+    max_total_bytes: 8192,
+    ..DecodeLimits::default()
+  }
+}
+
+/// `authenticated` is true if the query was sent with a valid session MAC
+async fn handle_client_query<S: DynMessageServer>(
+  peer: SocketAddr,
+  state: &State<S>,
   m: Sequence<ClientQuery>,
+  authenticated: bool,
 ) -> anyhow::Result<Vec<u8>> {
   log::debug!("received {:?}", m);
   let src = m.src;
 
-  let lock = srv.write().await;
+  let srv = &state.srv;
 
   // handle register
   if let ClientQuery::Register(name) = &m.content {
     log::debug!("handle register message");
     let name = name.clone();
-    match lock.handle_sequenced_message(m).await {
-      Ok(_) => (),
-      Err(ClientError::UnknownClient) => (),
-      Err(rr) => {
-        anyhow::bail!("Error when handling register message: {}", rr);
+    // the source of an unauthenticated query can be spoofed, it must not move its sequence
+    if authenticated {
+      match srv.handle_sequenced_message(m).await {
+        Ok(_) => (),
+        Err(ClientError::UnknownClient) => (),
+        Err(rr) => {
+          anyhow::bail!("Error when handling register message: {}", rr);
+        }
       }
     }
-    let id = lock
-      .register_local_client(src_ip, name)
+    let id = srv
+      .register_local_client(peer.ip(), name)
       .await
       .ok_or_else(|| anyhow::anyhow!("flagged as spammer"))?;
+    let secret = state.auth.lock().unwrap().register(id);
     let mut ocurs = Cursor::new(Vec::new());
-    encode::clientid(&mut ocurs, &id)?;
+    encode::registered(&mut ocurs, &Registered { id, secret })?;
     return Ok(ocurs.into_inner());
   }
 
-  match lock.handle_sequenced_message(m).await? {
+  // the handshake is not sequenced, for the same reason, its nonces prevent replays
+  if let ClientQuery::Auth(msg) = m.content {
+    let repl = state.auth.lock().unwrap().handle(peer, src, msg)?;
+    let mut ocurs = Cursor::new(Vec::new());
+    encode::auth(&mut ocurs, &repl)?;
+    return Ok(ocurs.into_inner());
+  }
+
+  // everything else requires an authenticated client
+  if !authenticated {
+    anyhow::bail!("{} is not authenticated", src);
+  }
+
+  match srv.handle_sequenced_message(m).await? {
     ClientQuery::Poll => {
-      let repl = lock.client_poll(src).await;
+      let repl = srv.client_poll(src).await;
       log::debug!(" -> poll {:?}", repl);
       let mut ocurs = Cursor::new(Vec::new());
       encode::client_poll_reply(&mut ocurs, &repl)?;
       Ok(ocurs.into_inner())
     }
     ClientQuery::ListUsers => {
-      let repl = lock.list_users().await;
+      let repl = srv.list_users().await;
       let mut ocurs = Cursor::new(Vec::new());
       encode::userlist(&mut ocurs, &repl)?;
       Ok(ocurs.into_inner())
@@ -101,62 +325,506 @@ async fn handle_client_query<S: MessageServer<DefaultChecker>>(
       anyhow::bail!("Unexpected register message from enrolled client")
     }
     ClientQuery::Message(msg) => {
-      let repl = lock.handle_client_message(src, msg).await;
+      let repl = srv.handle_client_message(src, msg).await;
+      for r in &repl {
+        if let ClientReply::Transfer(dest, m) = r {
+          if let Err(rr) = send_to_peer(state, *dest, m.clone()).await {
+            log::error!("Could not transfer message to {}: {}", dest, rr);
+          }
+        }
+      }
       let mut ocurs = Cursor::new(Vec::new());
       encode::client_replies(&mut ocurs, &repl)?;
       Ok(ocurs.into_inner())
     }
+    ClientQuery::Auth(_) => {
+      anyhow::bail!("Unexpected auth message")
+    }
+  }
+}
+
+/// the reply to a query that failed, the only reply format that can carry an error
+fn error_reply(rr: ClientError) -> Vec<u8> {
+  let mut ocurs = Cursor::new(Vec::new());
+  encode::client_replies(&mut ocurs, &[ClientReply::Error(rr)]).expect("writing to a vector");
+  ocurs.into_inner()
+}
+
+/// Handles an encoded query, and returns the encoded reply.
+///
+/// Once a client has a session, its queries must be followed by a MAC, and the replies are
+/// followed by a MAC too, see the `auth` module. A query without a valid MAC is handled as if the
+/// client had no session, so that it can only start a new handshake. Queries that can't be
+/// decoded or handled get a `ClientError` reply.
+async fn handle_client_packet<S: DynMessageServer>(
+  peer: SocketAddr,
+  state: &State<S>,
+  packet: &[u8],
+  limits: DecodeLimits,
+) -> Vec<u8> {
+  // the source is needed to find the session key, before the MAC can be checked
+  let mut header = packet;
+  let (seqid, src) = match decode::u128_with(&mut header, limits)
+    .and_then(|seqid| Ok((seqid, decode::clientid_with(&mut header, limits)?)))
+  {
+    Ok(header) => header,
+    Err(rr) => {
+      state.stats.decode_error("message", peer, &rr);
+      return error_reply(ClientError::Malformed(rr.to_string()));
+    }
+  };
+  let session = state.auth.lock().unwrap().session(&src);
+  let (payload, session) = match session {
+    None => (packet, None),
+    Some(session) => match session.open_query(packet) {
+      Ok(payload) => (payload, Some(session)),
+      Err(rr) => {
+        log::debug!("Unauthenticated message from {} ({}): {}", peer, src, rr);
+        (packet, None)
+      }
+    },
+  };
+  let mut msg = match decode::strict::<Sequence<ClientQuery>>(payload, limits) {
+    Ok(m) => match handle_client_query(peer, state, m, session.is_some()).await {
+      Ok(msg) => msg,
+      Err(rr) => {
+        log::error!("Error when handling message to {}: {}", peer, rr);
+        error_reply(
+          rr.downcast::<ClientError>()
+            .unwrap_or(ClientError::InternalError),
+        )
+      }
+    },
+    Err(rr) => {
+      state.stats.decode_error("message", peer, &rr);
+      error_reply(ClientError::Malformed(rr.to_string()))
+    }
+  };
+  if let Some(session) = session {
+    session.seal_reply(seqid, &mut msg);
   }
+  msg
 }
 
-async fn client_thread<S: MessageServer<DefaultChecker>>(
+async fn client_thread<S: DynMessageServer + 'static>(
   listen: IpAddr,
   port: u16,
-  srv: &RwLock<S>,
+  state: Arc<State<S>>,
 ) -> anyhow::Result<()> {
-  let socket = UdpSocket::bind((listen, port)).await?;
+  let socket = Arc::new(UdpSocket::bind((listen, port)).await?);
   log::info!("Listening for clients on {}", socket.local_addr()?);
+  // the datagrams are independent, they are handled concurrently by a fixed number of workers,
+  // and the socket is not read while they are all busy
+  let (tx, rx) = bounded::<(Vec<u8>, SocketAddr)>(CLIENT_WORKERS);
+  for _ in 0..CLIENT_WORKERS {
+    let (socket, state, rx) = (socket.clone(), state.clone(), rx.clone());
+    task::spawn(async move {
+      while let Ok((packet, peer)) = rx.recv().await {
+        let msg = handle_client_packet(peer, &state, &packet, datagram_limits()).await;
+        log::debug!("sending message {:?}", msg);
+        match socket.send_to(&msg, peer).await {
+          Ok(_) => (),
+          Err(rr) => log::error!("Error when sending message to {}: {}", peer, rr),
+        }
+      }
+    });
+  }
   let mut buf = vec![0u8; 8192];
   loop {
     let (n, peer) = socket.recv_from(&mut buf).await?;
-    let mut cursor = Cursor::new(buf[..n].to_vec());
-    match decode::sequence(&mut cursor, decode::client_query) {
-      Err(rr) => log::error!("Could not decode message from {}: {}", peer, rr),
-      Ok(m) => match handle_client_query(peer.ip(), srv, m).await {
-        Ok(msg) => {
-          log::debug!("sending message {:?}", msg);
-          match socket.send_to(&msg, peer).await {
-            Ok(_) => (),
-            Err(rr) => log::error!("Error when sending message to {}: {}", peer, rr),
+    tx.send((buf[..n].to_vec(), peer)).await?;
+  }
+}
+
+async fn client_connection<S: DynMessageServer>(
+  stream: TcpStream,
+  peer: SocketAddr,
+  state: &State<S>,
+) {
+  let mut rd = FrameReader::new(stream.clone());
+  let mut wr = stream;
+  loop {
+    let packet = match rd.recv_frame().await {
+      Ok(None) => break,
+      Ok(Some(packet)) => packet,
+      Err(rr) => {
+        state.stats.frame_error("message", peer, &rr);
+        break;
+      }
+    };
+    let msg = handle_client_packet(peer, state, &packet, DecodeLimits::default()).await;
+    log::debug!("sending message {:?}", msg);
+    if let Err(rr) = write_frame(&mut wr, &msg).await {
+      log::error!("Error when sending message to {}: {}", peer, rr);
+      break;
+    }
+  }
+  log::debug!("client connection from {} closed", peer);
+}
+
+async fn client_tcp_thread<S>(
+  listen: IpAddr,
+  port: u16,
+  state: Arc<State<S>>,
+) -> std::io::Result<()>
+where
+  S: DynMessageServer + 'static,
+{
+  let listener = TcpListener::bind((listen, port)).await?;
+  log::info!("Listening for clients on tcp/{}", listener.local_addr()?);
+  loop {
+    let (stream, peer) = listener.accept().await?;
+    let state = state.clone();
+    task::spawn(async move { client_connection(stream, peer, &state).await });
+  }
+}
+
+/// lets the server run its periodic tasks
+async fn tick_thread<S: DynMessageServer>(state: Arc<State<S>>) {
+  loop {
+    task::sleep(Duration::from_secs(1)).await;
+    state.srv.tick().await;
+  }
+}
+
+/// Reads admin commands on the standard input, one per line:
+///  * `dot [file]`: writes the federation seen by the server as a Graphviz document, on the
+///    standard output or in `file`
+///  * `latency`: prints the latency of each method of the server, with `--layer latency`
+///  * `deny <client id>` and `allow <client id>`: updates the denylist, with `--layer denylist`
+async fn admin_thread<S: DynMessageServer>(state: Arc<State<S>>) {
+  let stdin = async_std::io::stdin();
+  let mut line = String::new();
+  loop {
+    line.clear();
+    match stdin.read_line(&mut line).await {
+      Ok(0) => break,
+      Ok(_) => (),
+      Err(rr) => {
+        log::error!("Could not read admin command: {}", rr);
+        break;
+      }
+    }
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+      (None, _) => (),
+      (Some("dot"), file) => {
+        let Some(dot) = state.srv.topology_dot().await else {
+          log::error!("The server does not keep track of the topology");
+          continue;
+        };
+        match file {
+          None => print!("{}", dot),
+          Some(file) => {
+            if let Err(rr) = async_std::fs::write(file, dot).await {
+              log::error!("Could not write {}: {}", file, rr);
+            }
           }
         }
-        Err(rr) => log::error!("Error when handling message to {}: {}", peer, rr),
-      },
+      }
+      (Some("latency"), _) => {
+        let Some(latency) = &state.middleware.latency else {
+          log::error!("The latency layer is not enabled, see --layer");
+          continue;
+        };
+        for (method, histogram) in latency.histograms() {
+          println!(
+            "{}: {} calls, mean {:?}, p99 {:?}, max {:?}",
+            method,
+            histogram.count(),
+            histogram.mean().unwrap_or_default(),
+            histogram.quantile(0.99).unwrap_or_default(),
+            histogram.max()
+          );
+        }
+      }
+      (Some(command @ ("deny" | "allow")), client) => {
+        let Some(denylist) = &state.middleware.denylist else {
+          log::error!("The denylist layer is not enabled, see --layer");
+          continue;
+        };
+        match client.map(ClientId::from_str) {
+          Some(Ok(client)) if command == "deny" => denylist.deny(client),
+          Some(Ok(client)) => {
+            if !denylist.allow(client) {
+              log::warn!("{} was not denied", client);
+            }
+          }
+          _ => log::error!("Expected {} <client id>", command),
+        }
+      }
+      (Some(command), _) => log::error!(
+        "Unknown admin command {}, expected dot, latency, deny or allow",
+        command
+      ),
     }
   }
 }
 
 fn main() {
   pretty_env_logger::init();
-  let opt = Opt::from_args();
+  let mut opt = Opt::from_args();
+  if opt.list_impls {
+    for implementation in IMPLEMENTATIONS {
+      if implementation.ready {
+        println!("{}", implementation.name);
+      } else {
+        println!("{} (unfinished)", implementation.name);
+      }
+    }
+    return;
+  }
+  let Some(implementation) = Implementation::find(&opt.implementation) else {
+    log::error!(
+      "Unknown implementation {}, see --list-impls",
+      opt.implementation
+    );
+    return;
+  };
+  if !implementation.ready {
+    log::error!(
+      "The {} implementation is not finished, see --list-impls",
+      implementation.name
+    );
+    return;
+  }
+  if let Some(path) = &opt.config {
+    match config::PeerConfig::load(path) {
+      Ok(config) => {
+        opt.peers.extend(config.peers);
+        opt.peer_keys.extend(config.keys);
+      }
+      Err(rr) => {
+        log::error!("{}", rr);
+        return;
+      }
+    }
+  }
 
-  let server =
-    chatproto::solutions::sample::Server::new(DefaultChecker::default(), ServerId::default());
-  let clock = Arc::new(RwLock::new(server));
-  let slock = clock.clone();
+  let id = opt.id.unwrap_or_default();
+  log::info!("Server id is {}", id);
+  if opt.route_ttl <= opt.announce {
+    log::warn!("Routes expire before they are announced again, increase --route-ttl");
+  }
+  let settings = Settings {
+    route_ttl: Duration::from_secs(opt.route_ttl),
+    delayed_ttl: Duration::from_secs(opt.delayed_ttl),
+    ..Settings::default()
+  };
+  log::info!("Running the {} implementation", implementation.name);
+  let server = (implementation.new)(DefaultChecker::default(), id, &settings);
+  let mut middleware = Middleware::default();
+  let server = middleware.wrap(&opt.layers, server);
+  match &middleware.denylist {
+    Some(denylist) => opt.denied.iter().for_each(|client| denylist.deny(*client)),
+    None if !opt.denied.is_empty() => {
+      log::error!("--deny needs --layer denylist");
+      return;
+    }
+    None => (),
+  }
+  let mut state = State::new(server, id, opt.peer_keys, &settings);
+  state.middleware = middleware;
+  let state = Arc::new(state);
 
   task::block_on(async move {
-    let cchild = task::spawn(async move {
-      if let Err(rr) = client_thread(opt.clisten, opt.cport, &clock).await {
-        log::error!("{}", rr)
-      }
-    });
-    let schild = task::spawn(async move {
-      if let Err(rr) = server_thread(opt.slisten, opt.sport, &slock).await {
-        log::error!("{}", rr)
+    let mut children = Vec::new();
+    // outgoing handshakes use TCP if available, as large messages do not fit in datagrams
+    let mut connector_socket = None;
+    if opt.transport.udp() {
+      let socket = match UdpSocket::bind((opt.slisten, opt.sport)).await {
+        Ok(socket) => Arc::new(socket),
+        Err(rr) => {
+          log::error!("{}", rr);
+          return;
+        }
+      };
+      if !opt.transport.tcp() {
+        connector_socket = Some(socket.clone());
       }
-    });
-    cchild.await;
-    let _ = schild.cancel().await;
+      let cstate = state.clone();
+      children.push(task::spawn(async move {
+        if let Err(rr) = client_thread(opt.clisten, opt.cport, cstate).await {
+          log::error!("{}", rr)
+        }
+      }));
+      let sstate = state.clone();
+      children.push(task::spawn(async move {
+        if let Err(rr) = federation::server_thread(socket, &sstate).await {
+          log::error!("{}", rr)
+        }
+      }));
+    }
+    if opt.transport.tcp() {
+      let cstate = state.clone();
+      children.push(task::spawn(async move {
+        if let Err(rr) = client_tcp_thread(opt.clisten, opt.cport, cstate).await {
+          log::error!("{}", rr)
+        }
+      }));
+      let sstate = state.clone();
+      children.push(task::spawn(async move {
+        if let Err(rr) = federation::server_tcp_thread(opt.slisten, opt.sport, sstate).await {
+          log::error!("{}", rr)
+        }
+      }));
+    }
+    if !opt.peers.is_empty() {
+      task::spawn(federation::connector_thread(
+        opt.peers,
+        connector_socket,
+        state.clone(),
+      ));
+    }
+    task::spawn(tick_thread(state.clone()));
+    task::spawn(admin_thread(state.clone()));
+    task::spawn(federation::announce_thread(
+      Duration::from_secs(opt.announce),
+      state.clone(),
+    ));
+    for child in children {
+      child.await;
+    }
   });
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use chatproto::auth::Handshake;
+  use chatproto::messages::{ClientMessage, ClientPollReply};
+
+  fn state() -> State<Box<dyn DynMessageServer>> {
+    let id = ServerId::default();
+    let srv = (IMPLEMENTATIONS[0].new)(DefaultChecker::default(), id, &Settings::default());
+    State::new(srv, id, Vec::new(), &Settings::default())
+  }
+
+  async fn reply(state: &State<Box<dyn DynMessageServer>>, packet: &[u8]) -> Vec<ClientReply> {
+    let peer = SocketAddr::from(([127, 0, 0, 1], 1234));
+    let reply = handle_client_packet(peer, state, packet, datagram_limits()).await;
+    decode::client_replies(&mut &reply[..]).unwrap()
+  }
+
+  #[async_std::test]
+  async fn error_replies() {
+    let state = state();
+    match &reply(&state, &[12]).await[..] {
+      [ClientReply::Error(ClientError::Malformed(_))] => (),
+      r => panic!("unexpected reply {:?}", r),
+    }
+    let sq = Sequence {
+      seqid: 1,
+      src: ClientId::from(1),
+      content: ClientQuery::Poll,
+    };
+    let mut packet = Vec::new();
+    encode::sequence(&mut packet, &sq, encode::client_query).unwrap();
+    // not authenticated
+    assert_eq!(
+      reply(&state, &packet).await,
+      vec![ClientReply::Error(ClientError::InternalError)]
+    );
+    packet.push(0);
+    match &reply(&state, &packet).await[..] {
+      [ClientReply::Error(ClientError::Malformed(_))] => (),
+      r => panic!("unexpected reply {:?}", r),
+    }
+  }
+
+  fn query(seqid: u128, src: ClientId, content: ClientQuery) -> Vec<u8> {
+    let mut packet = Vec::new();
+    encode::sequence(
+      &mut packet,
+      &Sequence {
+        seqid,
+        src,
+        content,
+      },
+      encode::client_query,
+    )
+    .unwrap();
+    packet
+  }
+
+  #[async_std::test]
+  async fn spoofed_queries() {
+    let state = state();
+    let limits = datagram_limits();
+    let peer = SocketAddr::from(([127, 0, 0, 1], 1234));
+    let attacker = SocketAddr::from(([127, 0, 0, 2], 1234));
+    let register = query(0, ClientId::default(), ClientQuery::Register("a".into()));
+    let raw = handle_client_packet(peer, &state, &register, limits).await;
+    let Registered { id, secret } = decode::registered(&mut &raw[..]).unwrap();
+
+    let mut client = Handshake::new(id, secret);
+    let raw = handle_client_packet(
+      peer,
+      &state,
+      &query(1, id, ClientQuery::Auth(client.hello())),
+      limits,
+    )
+    .await;
+    let nonce = decode::auth(&mut &raw[..]).unwrap();
+    // spoofed queries with a huge sequence number, that can't replace the challenge
+    for content in [
+      ClientQuery::Auth(Handshake::new(id, secret).hello()),
+      ClientQuery::Register("b".into()),
+      ClientQuery::Poll,
+    ] {
+      handle_client_packet(attacker, &state, &query(u128::MAX, id, content), limits).await;
+    }
+    let response = ClientQuery::Auth(client.respond(&nonce).unwrap());
+    let raw = handle_client_packet(peer, &state, &query(2, id, response), limits).await;
+    let session = client
+      .finish(&decode::auth(&mut &raw[..]).unwrap())
+      .unwrap();
+
+    // the sequence was not moved by the spoofed queries
+    let mut poll = query(3, id, ClientQuery::Poll);
+    session.seal_query(&mut poll);
+    let raw = handle_client_packet(peer, &state, &poll, limits).await;
+    let payload = session.open_reply(3, &raw).unwrap();
+    assert_eq!(
+      decode::client_poll_reply(&mut &payload[..]).unwrap(),
+      ClientPollReply::Nothing
+    );
+  }
+
+  #[async_std::test]
+  async fn layers() {
+    assert!("nope".parse::<LayerKind>().is_err());
+    let layers: Vec<LayerKind> = ["denylist", "latency", "tracing"]
+      .iter()
+      .map(|l| l.parse().unwrap())
+      .collect();
+    let mut middleware = Middleware::default();
+    let id = ServerId::default();
+    let srv = (IMPLEMENTATIONS[0].new)(DefaultChecker::default(), id, &Settings::default());
+    let srv = middleware.wrap(&layers, srv);
+    assert_eq!(srv.group_name(), "reference");
+
+    let localhost = IpAddr::from([127, 0, 0, 1]);
+    let c1 = srv
+      .register_local_client(localhost, "c1".into())
+      .await
+      .unwrap();
+    let c2 = srv
+      .register_local_client(localhost, "c2".into())
+      .await
+      .unwrap();
+    middleware.denylist.as_ref().unwrap().deny(c1);
+    let text = ClientMessage::Text {
+      dest: c2,
+      content: "hello".into(),
+    };
+    assert_eq!(
+      srv.handle_client_message(c1, text).await,
+      vec![ClientReply::Error(ClientError::UnknownClient)]
+    );
+    let histograms = middleware.latency.as_ref().unwrap().histograms();
+    assert_eq!(histograms["register_local_client"].count(), 2);
+    // the denylist was added first, the latency layer is called before it
+    assert_eq!(histograms["handle_client_message"].count(), 1);
+  }
+}